@@ -1,5 +1,9 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer, Mint};
+use anchor_spl::token_2022::spl_token_2022::extension::{
+    BaseStateWithExtensions, ExtensionType, StateWithExtensions,
+};
+use anchor_spl::token_2022::spl_token_2022::state::Mint as MintState;
+use anchor_spl::token_interface::{self, Mint, TokenAccount, TokenInterface, TransferChecked};
 
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS");
 
@@ -12,9 +16,12 @@ pub mod pudl_factory {
         bond_amount: u64,
         min_base_fee_bps: u16,
         max_base_fee_bps: u16,
+        governance: Pubkey,
     ) -> Result<()> {
         let factory = &mut ctx.accounts.factory;
         factory.admin = ctx.accounts.admin.key();
+        factory.guardian = Pubkey::default();
+        factory.governance = governance;
         factory.treasury = ctx.accounts.treasury.key();
         factory.router = ctx.accounts.router.key();
         factory.bond_mint = ctx.accounts.bond_mint.key();
@@ -22,6 +29,11 @@ pub mod pudl_factory {
         factory.max_base_fee_bps = max_base_fee_bps;
         factory.min_base_fee_bps = min_base_fee_bps;
         factory.fee_scheduler_enabled = false;
+        factory.dynamic_fee_enabled = false;
+        factory.max_referral_fee_bps = 0;
+        factory.fee_discount_bps = [0; NUM_STAKE_TIERS];
+        factory.min_creator_fee_bps = 0;
+        factory.max_creator_fee_bps = 0;
         factory.total_pools = 0;
         factory.bump = ctx.bumps.factory;
 
@@ -39,6 +51,7 @@ pub mod pudl_factory {
         base_fee_bps: u16,
         bin_step: u16,
         initial_price_x64: u128,
+        fee_schedule: FeeSchedule,
     ) -> Result<()> {
         let factory = &mut ctx.accounts.factory;
         
@@ -46,18 +59,38 @@ pub mod pudl_factory {
             base_fee_bps >= factory.min_base_fee_bps && base_fee_bps <= factory.max_base_fee_bps,
             ErrorCode::InvalidFeeRange
         );
+        validate_mint(&ctx.accounts.base_mint)?;
+        validate_mint(&ctx.accounts.quote_mint)?;
+
+        if fee_schedule.mode != FeeScheduleMode::None {
+            require!(factory.fee_scheduler_enabled, ErrorCode::FeeSchedulerDisabled);
+            require!(
+                fee_schedule.cliff_fee_bps >= base_fee_bps && fee_schedule.cliff_fee_bps < 10000,
+                ErrorCode::InvalidFeeSchedule
+            );
+            require!(
+                fee_schedule.number_of_periods > 0 && fee_schedule.period_frequency > 0,
+                ErrorCode::InvalidFeeSchedule
+            );
+            require!(
+                fee_schedule.mode == FeeScheduleMode::Linear || fee_schedule.reduction_factor < 10000,
+                ErrorCode::InvalidFeeSchedule
+            );
+        }
 
         // Transfer bonded $PUDL to bond vault
-        token::transfer(
+        token_interface::transfer_checked(
             CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
-                Transfer {
+                TransferChecked {
                     from: ctx.accounts.user_pudl_account.to_account_info(),
+                    mint: ctx.accounts.bond_mint.to_account_info(),
                     to: ctx.accounts.bond_vault.to_account_info(),
                     authority: ctx.accounts.user.to_account_info(),
                 },
             ),
             factory.bond_amount,
+            ctx.accounts.bond_mint.decimals,
         )?;
 
         let pool = &mut ctx.accounts.pool_meta;
@@ -67,7 +100,8 @@ pub mod pudl_factory {
         pool.base_fee_bps = base_fee_bps;
         pool.bin_step = bin_step;
         pool.initial_price_x64 = initial_price_x64;
-        pool.pool_authority = ctx.accounts.pool_meta.key();
+        pool.fee_schedule = fee_schedule;
+        pool.pool_authority = pool.key();
         pool.bond_vault = ctx.accounts.bond_vault.key();
         pool.created_at = Clock::get()?.unix_timestamp;
         pool.flags = 1; // Active flag
@@ -94,24 +128,27 @@ pub mod pudl_factory {
         require!(pool.flags & 1 == 0, ErrorCode::PoolStillActive);
 
         let bond_amount = factory.bond_amount;
+        let pool_key = pool.key();
         let seeds = &[
             b"bond",
-            pool.key().as_ref(),
+            pool_key.as_ref(),
             &[ctx.bumps.bond_vault],
         ];
         let signer = &[&seeds[..]];
 
-        token::transfer(
+        token_interface::transfer_checked(
             CpiContext::new_with_signer(
                 ctx.accounts.token_program.to_account_info(),
-                Transfer {
+                TransferChecked {
                     from: ctx.accounts.bond_vault.to_account_info(),
+                    mint: ctx.accounts.bond_mint.to_account_info(),
                     to: ctx.accounts.creator_pudl_account.to_account_info(),
                     authority: ctx.accounts.bond_vault.to_account_info(),
                 },
                 signer,
             ),
             bond_amount,
+            ctx.accounts.bond_mint.decimals,
         )?;
 
         emit!(PoolClosed {
@@ -127,6 +164,9 @@ pub mod pudl_factory {
         new_bond_amount: Option<u64>,
         new_min_fee: Option<u16>,
         new_max_fee: Option<u16>,
+        fee_scheduler_enabled: Option<bool>,
+        max_referral_fee_bps: Option<u16>,
+        dynamic_fee_enabled: Option<bool>,
     ) -> Result<()> {
         let factory = &mut ctx.accounts.factory;
 
@@ -139,9 +179,137 @@ pub mod pudl_factory {
         if let Some(max) = new_max_fee {
             factory.max_base_fee_bps = max;
         }
+        if let Some(enabled) = fee_scheduler_enabled {
+            factory.fee_scheduler_enabled = enabled;
+        }
+        if let Some(max) = max_referral_fee_bps {
+            require!(max <= 10000, ErrorCode::InvalidFeeRange);
+            factory.max_referral_fee_bps = max;
+        }
+        if let Some(enabled) = dynamic_fee_enabled {
+            factory.dynamic_fee_enabled = enabled;
+        }
+
+        Ok(())
+    }
+
+    // Range for the share of swap fees, in bps, that pools pay their creator
+    pub fn set_creator_fee_bounds(
+        ctx: Context<SetParams>,
+        min_creator_fee_bps: u16,
+        max_creator_fee_bps: u16,
+    ) -> Result<()> {
+        require!(
+            min_creator_fee_bps <= max_creator_fee_bps && max_creator_fee_bps <= 10000,
+            ErrorCode::InvalidFeeRange
+        );
+
+        let factory = &mut ctx.accounts.factory;
+        factory.min_creator_fee_bps = min_creator_fee_bps;
+        factory.max_creator_fee_bps = max_creator_fee_bps;
+
+        Ok(())
+    }
+
+    // Swap fee discount, in bps of the fee, for each PUDL staking tier
+    pub fn set_fee_discounts(
+        ctx: Context<SetParams>,
+        fee_discount_bps: [u16; NUM_STAKE_TIERS],
+    ) -> Result<()> {
+        require!(
+            fee_discount_bps.iter().all(|discount| *discount <= 10000),
+            ErrorCode::InvalidFeeRange
+        );
+        ctx.accounts.factory.fee_discount_bps = fee_discount_bps;
+
+        emit!(FeeDiscountsUpdated { fee_discount_bps });
+
+        Ok(())
+    }
 
+    // Hands the creator role of a pool to `new_creator`. The pool program
+    // co-signs so that its own copy of the creator changes in the same call.
+    pub fn set_pool_creator(ctx: Context<SetPoolCreator>, new_creator: Pubkey) -> Result<()> {
+        ctx.accounts.pool_meta.creator = new_creator;
         Ok(())
     }
+
+    pub fn transfer_admin(ctx: Context<SetParams>, new_admin: Pubkey) -> Result<()> {
+        let factory = &mut ctx.accounts.factory;
+        let old = factory.admin;
+        factory.admin = new_admin;
+
+        emit!(RoleUpdated {
+            role: Role::Admin,
+            old,
+            new: new_admin,
+        });
+
+        Ok(())
+    }
+
+    // The guardian can pause pools alongside the admin. Pubkey::default()
+    // leaves the role unassigned.
+    pub fn set_guardian(ctx: Context<SetParams>, new_guardian: Pubkey) -> Result<()> {
+        let factory = &mut ctx.accounts.factory;
+        let old = factory.guardian;
+        factory.guardian = new_guardian;
+
+        emit!(RoleUpdated {
+            role: Role::Guardian,
+            old,
+            new: new_guardian,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_governance(ctx: Context<SetGovernance>, new_governance: Pubkey) -> Result<()> {
+        let factory = &mut ctx.accounts.factory;
+        let old = factory.governance;
+        factory.governance = new_governance;
+
+        emit!(RoleUpdated {
+            role: Role::Governance,
+            old,
+            new: new_governance,
+        });
+
+        Ok(())
+    }
+}
+
+// Token-2022 extensions a pool can hold. Anything that lets a third party
+// move or freeze vault funds, or needs extra accounts on every transfer, is
+// rejected.
+const SUPPORTED_MINT_EXTENSIONS: &[ExtensionType] = &[
+    ExtensionType::TransferFeeConfig,
+    ExtensionType::InterestBearingConfig,
+    ExtensionType::MetadataPointer,
+    ExtensionType::TokenMetadata,
+    ExtensionType::MintCloseAuthority,
+    ExtensionType::GroupPointer,
+    ExtensionType::TokenGroup,
+    ExtensionType::GroupMemberPointer,
+    ExtensionType::TokenGroupMember,
+];
+
+pub fn validate_mint(mint: &InterfaceAccount<Mint>) -> Result<()> {
+    let info = mint.to_account_info();
+    if *info.owner == anchor_spl::token::ID {
+        return Ok(());
+    }
+
+    let data = info.try_borrow_data()?;
+    let state = StateWithExtensions::<MintState>::unpack(&data)?;
+    for extension in state.get_extension_types()? {
+        require!(
+            SUPPORTED_MINT_EXTENSIONS.contains(&extension),
+            ErrorCode::UnsupportedMintExtension
+        );
+    }
+
+    Ok(())
 }
 
 #[derive(Accounts)]
@@ -155,7 +323,7 @@ pub struct InitializeFactory<'info> {
     )]
     pub factory: Account<'info, Factory>,
     
-    pub bond_mint: Account<'info, Mint>,
+    pub bond_mint: InterfaceAccount<'info, Mint>,
     
     /// CHECK: Treasury program
     pub treasury: AccountInfo<'info>,
@@ -191,15 +359,19 @@ pub struct CreatePool<'info> {
     #[account(
         init,
         payer = user,
-        token::mint = factory.bond_mint,
+        token::mint = bond_mint,
         token::authority = bond_vault,
+        token::token_program = token_program,
         seeds = [b"bond", pool_meta.key().as_ref()],
         bump
     )]
-    pub bond_vault: Account<'info, TokenAccount>,
+    pub bond_vault: InterfaceAccount<'info, TokenAccount>,
 
-    pub base_mint: Account<'info, Mint>,
-    pub quote_mint: Account<'info, Mint>,
+    #[account(address = factory.bond_mint)]
+    pub bond_mint: InterfaceAccount<'info, Mint>,
+
+    pub base_mint: InterfaceAccount<'info, Mint>,
+    pub quote_mint: InterfaceAccount<'info, Mint>,
 
     #[account(mut)]
     pub user: Signer<'info>,
@@ -208,9 +380,9 @@ pub struct CreatePool<'info> {
         mut,
         constraint = user_pudl_account.mint == factory.bond_mint
     )]
-    pub user_pudl_account: Account<'info, TokenAccount>,
+    pub user_pudl_account: InterfaceAccount<'info, TokenAccount>,
 
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
 }
@@ -233,14 +405,38 @@ pub struct ClosePool<'info> {
         seeds = [b"bond", pool_meta.key().as_ref()],
         bump
     )]
-    pub bond_vault: Account<'info, TokenAccount>,
+    pub bond_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = factory.bond_mint)]
+    pub bond_mint: InterfaceAccount<'info, Mint>,
 
     pub creator: Signer<'info>,
 
     #[account(mut)]
-    pub creator_pudl_account: Account<'info, TokenAccount>,
+    pub creator_pudl_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SetPoolCreator<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool_meta.base_mint.as_ref(), pool_meta.quote_mint.as_ref(), &pool_meta.bin_step.to_le_bytes()],
+        bump = pool_meta.bump,
+        constraint = pool_meta.creator == creator.key() @ ErrorCode::Unauthorized
+    )]
+    pub pool_meta: Account<'info, PoolMeta>,
 
-    pub token_program: Program<'info, Token>,
+    // The pool program's account for the same pair and bin step
+    #[account(
+        seeds = [b"pool", pool_meta.base_mint.as_ref(), pool_meta.quote_mint.as_ref(), &pool_meta.bin_step.to_le_bytes()],
+        bump,
+        seeds::program = POOL_PROGRAM_ID
+    )]
+    pub pool: Signer<'info>,
+
+    pub creator: Signer<'info>,
 }
 
 #[derive(Accounts)]
@@ -256,9 +452,24 @@ pub struct SetParams<'info> {
     pub admin: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct SetGovernance<'info> {
+    #[account(
+        mut,
+        seeds = [b"factory"],
+        bump = factory.bump,
+        constraint = factory.governance == governance.key() @ ErrorCode::Unauthorized
+    )]
+    pub factory: Account<'info, Factory>,
+
+    pub governance: Signer<'info>,
+}
+
 #[account]
 pub struct Factory {
     pub admin: Pubkey,
+    pub guardian: Pubkey,
+    pub governance: Pubkey,
     pub treasury: Pubkey,
     pub router: Pubkey,
     pub bond_mint: Pubkey,
@@ -266,14 +477,36 @@ pub struct Factory {
     pub max_base_fee_bps: u16,
     pub min_base_fee_bps: u16,
     pub fee_scheduler_enabled: bool,
+    // New pools charge the volatility-based variable fee on top of the base fee
+    pub dynamic_fee_enabled: bool,
+    // Most of a pool's protocol fee that may go to swap referrers
+    pub max_referral_fee_bps: u16,
+    pub fee_discount_bps: [u16; NUM_STAKE_TIERS],
+    // Bounds for the creator's share of swap fees
+    pub min_creator_fee_bps: u16,
+    pub max_creator_fee_bps: u16,
     pub total_pools: u64,
     pub bump: u8,
 }
 
 impl Factory {
-    pub const LEN: usize = 32 + 32 + 32 + 32 + 8 + 2 + 2 + 1 + 8 + 1;
+    pub const LEN: usize =
+        32 + 32 + 32 + 32 + 32 + 32 + 8 + 2 + 2 + 1 + 1 + 2 + 2 * NUM_STAKE_TIERS + 2 + 2 + 8 + 1;
+
+    pub fn can_pause(&self, key: &Pubkey) -> bool {
+        *key == self.admin || (self.guardian != Pubkey::default() && *key == self.guardian)
+    }
+
+    pub fn fee_discount_bps(&self, tier: u8) -> u16 {
+        self.fee_discount_bps.get(tier as usize).copied().unwrap_or(0)
+    }
 }
 
+pub const POOL_PROGRAM_ID: Pubkey = pubkey!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnT");
+
+// Tiers 0 to 3 as computed by pudl_staking::calculate_tier
+pub const NUM_STAKE_TIERS: usize = 4;
+
 #[account]
 pub struct PoolMeta {
     pub base_mint: Pubkey,
@@ -286,11 +519,73 @@ pub struct PoolMeta {
     pub bond_vault: Pubkey,
     pub created_at: i64,
     pub flags: u32,
+    pub fee_schedule: FeeSchedule,
     pub bump: u8,
 }
 
 impl PoolMeta {
-    pub const LEN: usize = 32 + 32 + 32 + 2 + 2 + 16 + 32 + 32 + 8 + 4 + 1;
+    pub const LEN: usize = 32 + 32 + 32 + 2 + 2 + 16 + 32 + 32 + 8 + 4 + FeeSchedule::LEN + 1;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FeeScheduleMode {
+    #[default]
+    None,
+    // cliff_fee_bps - reduction_factor * period
+    Linear,
+    // cliff_fee_bps * (1 - reduction_factor / 10000) ^ period
+    Exponential,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScheduleUnit {
+    #[default]
+    Slot,
+    Timestamp,
+}
+
+impl ScheduleUnit {
+    pub fn current(&self, clock: &Clock) -> u64 {
+        match self {
+            ScheduleUnit::Slot => clock.slot,
+            ScheduleUnit::Timestamp => clock.unix_timestamp.max(0) as u64,
+        }
+    }
+}
+
+// Launch fee that starts at cliff_fee_bps when the pool activates and steps
+// down every period_frequency slots or seconds until it reaches the base fee
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FeeSchedule {
+    pub mode: FeeScheduleMode,
+    pub unit: ScheduleUnit,
+    pub cliff_fee_bps: u16,
+    pub number_of_periods: u16,
+    pub period_frequency: u64,
+    pub reduction_factor: u16,
+}
+
+impl FeeSchedule {
+    pub const LEN: usize = 1 + 1 + 2 + 2 + 8 + 2;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    Guardian,
+    Governance,
+}
+
+#[event]
+pub struct RoleUpdated {
+    pub role: Role,
+    pub old: Pubkey,
+    pub new: Pubkey,
+}
+
+#[event]
+pub struct FeeDiscountsUpdated {
+    pub fee_discount_bps: [u16; NUM_STAKE_TIERS],
 }
 
 #[event]
@@ -328,4 +623,10 @@ pub enum ErrorCode {
     PoolStillActive,
     #[msg("Unauthorized")]
     Unauthorized,
+    #[msg("Fee scheduler is disabled")]
+    FeeSchedulerDisabled,
+    #[msg("Invalid fee schedule")]
+    InvalidFeeSchedule,
+    #[msg("Mint has an unsupported token extension")]
+    UnsupportedMintExtension,
 }
@@ -1,5 +1,23 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer, Mint};
+use anchor_lang::solana_program::instruction::{get_stack_height, TRANSACTION_LEVEL_STACK_HEIGHT};
+use anchor_lang::solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked,
+};
+use anchor_lang::system_program;
+use anchor_spl::associated_token::AssociatedToken;
+use anchor_spl::token_2022::spl_token_2022::instruction::AuthorityType;
+use anchor_spl::token_2022_extensions::spl_pod::optional_keys::OptionalNonZeroPubkey;
+use anchor_spl::token_2022_extensions::spl_token_metadata_interface::state::TokenMetadata;
+use anchor_spl::token_2022::spl_token_2022::extension::transfer_fee::TransferFeeConfig;
+use anchor_spl::token_2022::spl_token_2022::extension::{BaseStateWithExtensions, StateWithExtensions};
+use anchor_spl::token_2022::spl_token_2022::state::Mint as MintState;
+use anchor_spl::token_interface::{
+    self, Mint, Token2022, TokenAccount, TokenInterface, TransferChecked,
+};
+use pudl_factory::{FeeSchedule, FeeScheduleMode, ScheduleUnit};
+use solana_sha256_hasher::hashv;
+use std::cell::RefMut;
+use std::ops::DerefMut;
 
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnT");
 
@@ -7,13 +25,35 @@ declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnT");
 pub mod pudl_dlmm {
     use super::*;
 
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize_pool(
         ctx: Context<InitializePool>,
         base_fee_bps: u16,
         protocol_fee_bps: u16,
+        creator_fee_bps: u16,
         bin_step: u16,
         active_bin_id: i32,
+        fee_params: DynamicFeeParams,
+        activation_type: ScheduleUnit,
+        activation_point: Option<u64>,
     ) -> Result<()> {
+        require!(bin_step > 0, ErrorCode::InvalidBinStep);
+        require!(
+            base_fee_bps == ctx.accounts.pool_meta.base_fee_bps,
+            ErrorCode::InvalidFeeParams
+        );
+        get_price_from_id(active_bin_id, bin_step)?;
+
+        let dynamic_fee_enabled = ctx.accounts.factory.dynamic_fee_enabled;
+        if dynamic_fee_enabled {
+            fee_params.validate(base_fee_bps, ctx.accounts.factory.max_base_fee_bps)?;
+        }
+
+        let clock = Clock::get()?;
+        let now_point = activation_type.current(&clock);
+        let activation_point = activation_point.unwrap_or(now_point);
+        require!(activation_point >= now_point, ErrorCode::InvalidActivationPoint);
+
         let pool = &mut ctx.accounts.pool;
         pool.factory = ctx.accounts.factory.key();
         pool.creator = ctx.accounts.creator.key();
@@ -25,67 +65,388 @@ pub mod pudl_dlmm {
         pool.bin_step = bin_step;
         pool.active_bin_id = active_bin_id;
         pool.protocol_fee_bps = protocol_fee_bps;
+        // Creators pick their share once within the factory range and can
+        // only lower it afterwards
+        require!(
+            creator_fee_bps >= ctx.accounts.factory.min_creator_fee_bps
+                && creator_fee_bps <= ctx.accounts.factory.max_creator_fee_bps
+                && protocol_fee_bps as u128 + creator_fee_bps as u128 <= BASIS_POINT_MAX,
+            ErrorCode::InvalidFeeParams
+        );
+        pool.creator_fee_bps = creator_fee_bps;
         pool.total_volume = 0;
         pool.total_fees = 0;
-        pool.paused = false;
+        pool.status = PoolStatus::Active;
+        pool.total_liquidity_shares = 0;
+        pool.dynamic_fee_enabled = dynamic_fee_enabled;
+        pool.fee_params = fee_params;
+        pool.volatility = VolatilityState {
+            index_reference: active_bin_id,
+            last_update_timestamp: clock.unix_timestamp,
+            ..Default::default()
+        };
+        pool.fee_schedule = ctx.accounts.pool_meta.fee_schedule;
+        // The launch fee starts counting down when trading opens
+        pool.fee_schedule_start = if pool.fee_schedule.unit == activation_type {
+            activation_point
+        } else {
+            pool.fee_schedule.unit.current(&clock)
+        };
+        pool.activation_type = activation_type;
+        pool.activation_point = activation_point;
+        pool.alpha_vault_pending = false;
+        pool.reserve_base = 0;
+        pool.reserve_quote = 0;
+        pool.order_base = 0;
+        pool.order_quote = 0;
+        pool.liquidity_base = 0;
+        pool.liquidity_quote = 0;
+        pool.lp_fee_base = 0;
+        pool.lp_fee_quote = 0;
         pool.bump = ctx.bumps.pool;
 
+        let oracle = &mut ctx.accounts.oracle;
+        oracle.pool = pool.key();
+        oracle.idx = 0;
+        oracle.observations = vec![Observation::default(); DEFAULT_ORACLE_LENGTH];
+        oracle.update(clock.unix_timestamp, active_bin_id)?;
+        oracle.bump = ctx.bumps.oracle;
+
+        Ok(())
+    }
+
+    pub fn increase_oracle_length(
+        ctx: Context<IncreaseOracleLength>,
+        length_to_add: u16,
+    ) -> Result<()> {
+        let length = ctx.accounts.oracle.observations.len() + length_to_add as usize;
+        require!(
+            length_to_add > 0 && length <= MAX_ORACLE_LENGTH,
+            ErrorCode::InvalidOracleLength
+        );
+        if length > PERMISSIONLESS_ORACLE_LENGTH {
+            require_keys_eq!(ctx.accounts.payer.key(), ctx.accounts.pool.creator, ErrorCode::Unauthorized);
+        }
+
+        let oracle = &mut ctx.accounts.oracle;
+        oracle.grow(length_to_add as usize);
+
+        emit!(OracleLengthIncreased {
+            pool: oracle.pool,
+            length: oracle.observations.len() as u64,
+        });
+
+        Ok(())
+    }
+
+    pub fn get_twap(ctx: Context<GetTwap>, window: u64) -> Result<TwapQuote> {
+        let pool = &ctx.accounts.pool;
+        let now = Clock::get()?.unix_timestamp;
+        let (average_bin_id, covered) = ctx.accounts.oracle.twap(now, pool.active_bin_id, window)?;
+
+        Ok(TwapQuote {
+            average_bin_id,
+            price_x64: get_price_from_id(average_bin_id, pool.bin_step)?,
+            window: covered,
+        })
+    }
+
+    pub fn initialize_bin_array(ctx: Context<InitializeBinArray>, index: i64) -> Result<()> {
+        let min_index = bin_array_index(MIN_BIN_ID);
+        let max_index = bin_array_index(MAX_BIN_ID);
+        require!(
+            index >= min_index && index <= max_index,
+            ErrorCode::InvalidBinArray
+        );
+
+        let bin_array = &mut ctx.accounts.bin_array.load_init()?;
+        bin_array.pool = ctx.accounts.pool.key();
+        bin_array.index = index;
+
+        Ok(())
+    }
+
+    pub fn open_position(
+        ctx: Context<OpenPosition>,
+        lower_bin_id: i32,
+        upper_bin_id: i32,
+    ) -> Result<()> {
+        require!(
+            lower_bin_id <= upper_bin_id
+                && upper_bin_id - lower_bin_id < MAX_BIN_PER_POSITION as i32,
+            ErrorCode::InvalidBinRange
+        );
+        require!(
+            lower_bin_id >= MIN_BIN_ID && upper_bin_id <= MAX_BIN_ID,
+            ErrorCode::InvalidBinId
+        );
+
+        let position = &mut ctx.accounts.position;
+        position.nft_mint = ctx.accounts.position_mint.key();
+        position.pool = ctx.accounts.pool.key();
+        position.lower_bin_id = lower_bin_id;
+        position.upper_bin_id = upper_bin_id;
+
+        let pool = &ctx.accounts.pool;
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        // The metadata lives in the mint itself, so fund the extra space
+        // before token-2022 reallocates it
+        let metadata = TokenMetadata {
+            update_authority: OptionalNonZeroPubkey::try_from(Some(pool.key()))?,
+            mint: ctx.accounts.position_mint.key(),
+            name: POSITION_NFT_NAME.to_string(),
+            symbol: POSITION_NFT_SYMBOL.to_string(),
+            uri: POSITION_NFT_URI.to_string(),
+            additional_metadata: vec![],
+        };
+        let mint_info = ctx.accounts.position_mint.to_account_info();
+        let new_len = mint_info.data_len() + metadata.tlv_size_of()?;
+        let lamports = Rent::get()?.minimum_balance(new_len).saturating_sub(mint_info.lamports());
+        if lamports > 0 {
+            system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.user.to_account_info(),
+                        to: mint_info.clone(),
+                    },
+                ),
+                lamports,
+            )?;
+        }
+
+        token_interface::token_metadata_initialize(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_2022_program.to_account_info(),
+                token_interface::TokenMetadataInitialize {
+                    program_id: ctx.accounts.token_2022_program.to_account_info(),
+                    metadata: mint_info.clone(),
+                    update_authority: pool.to_account_info(),
+                    mint_authority: pool.to_account_info(),
+                    mint: mint_info.clone(),
+                },
+                signer,
+            ),
+            metadata.name,
+            metadata.symbol,
+            metadata.uri,
+        )?;
+
+        token_interface::mint_to(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_2022_program.to_account_info(),
+                token_interface::MintTo {
+                    mint: mint_info.clone(),
+                    to: ctx.accounts.position_token_account.to_account_info(),
+                    authority: pool.to_account_info(),
+                },
+                signer,
+            ),
+            1,
+        )?;
+
+        // Drop the mint authority so the supply stays at exactly one
+        token_interface::set_authority(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_2022_program.to_account_info(),
+                token_interface::SetAuthority {
+                    current_authority: pool.to_account_info(),
+                    account_or_mint: mint_info,
+                },
+                signer,
+            ),
+            AuthorityType::MintTokens,
+            None,
+        )?;
+
+        emit!(PositionOpened {
+            pool: pool.key(),
+            user: ctx.accounts.user.key(),
+            position: position.key(),
+            lower_bin_id,
+            upper_bin_id,
+        });
+
+        Ok(())
+    }
+
+    pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+        let position = &ctx.accounts.position;
+        require!(position.is_empty(), ErrorCode::PositionNotEmpty);
+        require!(
+            position.fees_owed_base == 0 && position.fees_owed_quote == 0,
+            ErrorCode::PositionNotEmpty
+        );
+        require!(
+            position.rewards_owed.iter().all(|owed| *owed == 0),
+            ErrorCode::PositionNotEmpty
+        );
+
+        token_interface::burn(
+            CpiContext::new(
+                ctx.accounts.token_2022_program.to_account_info(),
+                token_interface::Burn {
+                    mint: ctx.accounts.position_mint.to_account_info(),
+                    from: ctx.accounts.position_token_account.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            1,
+        )?;
+
+        token_interface::close_account(CpiContext::new(
+            ctx.accounts.token_2022_program.to_account_info(),
+            token_interface::CloseAccount {
+                account: ctx.accounts.position_token_account.to_account_info(),
+                destination: ctx.accounts.user.to_account_info(),
+                authority: ctx.accounts.user.to_account_info(),
+            },
+        ))?;
+
+        let pool = &ctx.accounts.pool;
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        token_interface::close_account(CpiContext::new_with_signer(
+            ctx.accounts.token_2022_program.to_account_info(),
+            token_interface::CloseAccount {
+                account: ctx.accounts.position_mint.to_account_info(),
+                destination: ctx.accounts.user.to_account_info(),
+                authority: pool.to_account_info(),
+            },
+            signer,
+        ))?;
+
+        emit!(PositionClosed {
+            pool: position.pool,
+            user: ctx.accounts.user.key(),
+            position: position.key(),
+        });
+
         Ok(())
     }
 
     pub fn add_liquidity(
         ctx: Context<AddLiquidity>,
-        bin_id: i32,
         base_amount: u64,
         quote_amount: u64,
+        shape: LiquidityShape,
     ) -> Result<()> {
-        let pool = &ctx.accounts.pool;
-        require!(!pool.paused, ErrorCode::PoolPaused);
+        let pool = &mut ctx.accounts.pool;
+        require!(pool.status.can_deposit(), ErrorCode::PoolPaused);
+        pool.require_no_flash_loan()?;
+        pool.require_can_seed(&Clock::get()?, &ctx.accounts.user.key())?;
 
-        if base_amount > 0 {
-            token::transfer(
+        let position = &mut ctx.accounts.position;
+        let lower_bin_id = position.lower_bin_id;
+        let upper_bin_id = position.upper_bin_id;
+
+        // Only what survives the transfer fee reaches the vaults
+        let base_mint = ctx.accounts.base_mint.to_account_info();
+        let quote_mint = ctx.accounts.quote_mint.to_account_info();
+        let distribution = distribute_liquidity(
+            &shape,
+            lower_bin_id,
+            upper_bin_id,
+            pool.active_bin_id,
+            base_amount - transfer_fee(&base_mint, base_amount)?,
+            quote_amount - transfer_fee(&quote_mint, quote_amount)?,
+        )?;
+
+        let loaders = bin_array_pair(&ctx.accounts.bin_array_lower, &ctx.accounts.bin_array_upper);
+        let mut bins = BinArrays::load_mut(&loaders)?;
+        pool.update_rewards(&mut bins, Clock::get()?.unix_timestamp)?;
+        position.update_fees(&mut bins)?;
+
+        let mut base_deposited = 0u64;
+        let mut quote_deposited = 0u64;
+        let mut shares_added = 0u128;
+        for (bin_id, bin_base, bin_quote) in distribution {
+            if bin_base == 0 && bin_quote == 0 {
+                continue;
+            }
+
+            let price = get_price_from_id(bin_id, pool.bin_step)?;
+            let shares = bins.bin(bin_id)?.deposit(bin_base, bin_quote, price)?;
+            if shares == 0 {
+                continue;
+            }
+
+            *position.shares_mut(bin_id)? += shares;
+            shares_added += shares;
+            base_deposited += bin_base;
+            quote_deposited += bin_quote;
+        }
+        drop(bins);
+        require!(base_deposited > 0 || quote_deposited > 0, ErrorCode::ZeroLiquidity);
+
+        if base_deposited > 0 {
+            token_interface::transfer_checked(
                 CpiContext::new(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
+                    ctx.accounts.base_token_program.to_account_info(),
+                    TransferChecked {
                         from: ctx.accounts.user_base_account.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
                         to: ctx.accounts.base_vault.to_account_info(),
                         authority: ctx.accounts.user.to_account_info(),
                     },
                 ),
-                base_amount,
+                amount_with_transfer_fee(&base_mint, base_deposited)?,
+                ctx.accounts.base_mint.decimals,
             )?;
         }
 
-        if quote_amount > 0 {
-            token::transfer(
+        if quote_deposited > 0 {
+            token_interface::transfer_checked(
                 CpiContext::new(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
+                    ctx.accounts.quote_token_program.to_account_info(),
+                    TransferChecked {
                         from: ctx.accounts.user_quote_account.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
                         to: ctx.accounts.quote_vault.to_account_info(),
                         authority: ctx.accounts.user.to_account_info(),
                     },
                 ),
-                quote_amount,
+                amount_with_transfer_fee(&quote_mint, quote_deposited)?,
+                ctx.accounts.quote_mint.decimals,
             )?;
         }
 
-        let position = &mut ctx.accounts.position;
-        position.owner = ctx.accounts.user.key();
-        position.pool = pool.key();
-        position.lower_bin_id = bin_id;
-        position.upper_bin_id = bin_id;
-        position.base_amount += base_amount;
-        position.quote_amount += quote_amount;
-        position.fee_debt_base = 0;
-        position.fee_debt_quote = 0;
+        position.base_amount += base_deposited;
+        position.quote_amount += quote_deposited;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.total_liquidity_shares = pool
+            .total_liquidity_shares
+            .checked_add(shares_added)
+            .ok_or(ErrorCode::MathOverflow)?;
+        pool.liquidity_base += base_deposited;
+        pool.liquidity_quote += quote_deposited;
+        pool.add_reserves(base_deposited, quote_deposited)?;
+        pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
 
         emit!(LiquidityAdded {
             pool: pool.key(),
             user: ctx.accounts.user.key(),
-            bin_id,
-            base_amount,
-            quote_amount,
+            position: position.key(),
+            lower_bin_id,
+            upper_bin_id,
+            base_amount: base_deposited,
+            quote_amount: quote_deposited,
         });
 
         Ok(())
@@ -96,12 +457,34 @@ pub mod pudl_dlmm {
         bps: u16,
     ) -> Result<()> {
         require!(bps <= 10000, ErrorCode::InvalidBps);
+        require!(ctx.accounts.pool.status.can_withdraw(), ErrorCode::PoolPaused);
+        ctx.accounts.pool.require_no_flash_loan()?;
 
         let position = &mut ctx.accounts.position;
-        let pool = &ctx.accounts.pool;
+        let pool = &mut ctx.accounts.pool;
+
+        let loaders = bin_array_pair(&ctx.accounts.bin_array_lower, &ctx.accounts.bin_array_upper);
+        let mut bins = BinArrays::load_mut(&loaders)?;
+        pool.update_rewards(&mut bins, Clock::get()?.unix_timestamp)?;
+        position.update_fees(&mut bins)?;
 
-        let base_to_remove = (position.base_amount as u128 * bps as u128 / 10000) as u64;
-        let quote_to_remove = (position.quote_amount as u128 * bps as u128 / 10000) as u64;
+        let mut base_to_remove = 0u64;
+        let mut quote_to_remove = 0u64;
+        let mut shares_removed = 0u128;
+        for bin_id in position.lower_bin_id..=position.upper_bin_id {
+            let shares = position.shares_mut(bin_id)?;
+            let shares_to_remove = *shares * bps as u128 / 10000;
+            if shares_to_remove == 0 {
+                continue;
+            }
+
+            let (bin_base, bin_quote) = bins.bin(bin_id)?.withdraw(shares_to_remove)?;
+            *shares -= shares_to_remove;
+            shares_removed += shares_to_remove;
+            base_to_remove += bin_base;
+            quote_to_remove += bin_quote;
+        }
+        drop(bins);
 
         let seeds = &[
             b"pool",
@@ -113,41 +496,53 @@ pub mod pudl_dlmm {
         let signer = &[&seeds[..]];
 
         if base_to_remove > 0 {
-            token::transfer(
+            token_interface::transfer_checked(
                 CpiContext::new_with_signer(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
+                    ctx.accounts.base_token_program.to_account_info(),
+                    TransferChecked {
                         from: ctx.accounts.base_vault.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
                         to: ctx.accounts.user_base_account.to_account_info(),
                         authority: pool.to_account_info(),
                     },
                     signer,
                 ),
                 base_to_remove,
+                ctx.accounts.base_mint.decimals,
             )?;
         }
 
         if quote_to_remove > 0 {
-            token::transfer(
+            token_interface::transfer_checked(
                 CpiContext::new_with_signer(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
+                    ctx.accounts.quote_token_program.to_account_info(),
+                    TransferChecked {
                         from: ctx.accounts.quote_vault.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
                         to: ctx.accounts.user_quote_account.to_account_info(),
                         authority: pool.to_account_info(),
                     },
                     signer,
                 ),
                 quote_to_remove,
+                ctx.accounts.quote_mint.decimals,
             )?;
         }
 
-        position.base_amount -= base_to_remove;
-        position.quote_amount -= quote_to_remove;
+        position.base_amount -= (position.base_amount as u128 * bps as u128 / 10000) as u64;
+        position.quote_amount -= (position.quote_amount as u128 * bps as u128 / 10000) as u64;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.total_liquidity_shares = pool.total_liquidity_shares.saturating_sub(shares_removed);
+        pool.liquidity_base -= base_to_remove;
+        pool.liquidity_quote -= quote_to_remove;
+        pool.sub_reserves(base_to_remove, quote_to_remove)?;
+        pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
 
         emit!(LiquidityRemoved {
             pool: pool.key(),
             user: ctx.accounts.user.key(),
+            position: position.key(),
             base_amount: base_to_remove,
             quote_amount: quote_to_remove,
         });
@@ -155,364 +550,6334 @@ pub mod pudl_dlmm {
         Ok(())
     }
 
-    pub fn swap_exact_in(
-        ctx: Context<Swap>,
-        amount_in: u64,
-        min_out: u64,
-        swap_base_for_quote: bool,
-    ) -> Result<()> {
-        let pool = &mut ctx.accounts.pool;
-        require!(!pool.paused, ErrorCode::PoolPaused);
+    pub fn claim_fees(ctx: Context<ClaimFees>) -> Result<()> {
+        require!(ctx.accounts.pool.status.can_withdraw(), ErrorCode::PoolPaused);
+        ctx.accounts.pool.require_no_flash_loan()?;
+        let position = &mut ctx.accounts.position;
+        let pool = &ctx.accounts.pool;
 
-        let effective_fee_bps = pool.base_fee_bps;
-        
-        let fee_amount = (amount_in as u128 * effective_fee_bps as u128 / 10000) as u64;
-        let protocol_fee = (fee_amount as u128 * pool.protocol_fee_bps as u128 / 10000) as u64;
-        let amount_after_fee = amount_in - fee_amount;
-        
-        let amount_out = calculate_swap_output(amount_after_fee);
-        require!(amount_out >= min_out, ErrorCode::SlippageExceeded);
+        let loaders = bin_array_pair(&ctx.accounts.bin_array_lower, &ctx.accounts.bin_array_upper);
+        let mut bins = BinArrays::load_mut(&loaders)?;
+        position.update_fees(&mut bins)?;
+        drop(bins);
 
-        if swap_base_for_quote {
-            token::transfer(
-                CpiContext::new(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
-                        from: ctx.accounts.user_base_account.to_account_info(),
-                        to: ctx.accounts.base_vault.to_account_info(),
-                        authority: ctx.accounts.user.to_account_info(),
-                    },
-                ),
-                amount_in,
-            )?;
+        let base_fees = position.fees_owed_base;
+        let quote_fees = position.fees_owed_quote;
 
-            let seeds = &[
-                b"pool",
-                pool.base_mint.as_ref(),
-                pool.quote_mint.as_ref(),
-                &pool.bin_step.to_le_bytes(),
-                &[pool.bump],
-            ];
-            let signer = &[&seeds[..]];
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
 
-            token::transfer(
+        if base_fees > 0 {
+            token_interface::transfer_checked(
                 CpiContext::new_with_signer(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
-                        from: ctx.accounts.quote_vault.to_account_info(),
-                        to: ctx.accounts.user_quote_account.to_account_info(),
+                    ctx.accounts.base_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.base_vault.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
+                        to: ctx.accounts.user_base_account.to_account_info(),
                         authority: pool.to_account_info(),
                     },
                     signer,
                 ),
-                amount_out,
+                base_fees,
+                ctx.accounts.base_mint.decimals,
             )?;
-        } else {
-            token::transfer(
-                CpiContext::new(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
-                        from: ctx.accounts.user_quote_account.to_account_info(),
-                        to: ctx.accounts.quote_vault.to_account_info(),
-                        authority: ctx.accounts.user.to_account_info(),
-                    },
-                ),
-                amount_in,
-            )?;
-
-            let seeds = &[
-                b"pool",
-                pool.base_mint.as_ref(),
-                pool.quote_mint.as_ref(),
-                &pool.bin_step.to_le_bytes(),
-                &[pool.bump],
-            ];
-            let signer = &[&seeds[..]];
+        }
 
-            token::transfer(
+        if quote_fees > 0 {
+            token_interface::transfer_checked(
                 CpiContext::new_with_signer(
-                    ctx.accounts.token_program.to_account_info(),
-                    Transfer {
-                        from: ctx.accounts.base_vault.to_account_info(),
-                        to: ctx.accounts.user_base_account.to_account_info(),
+                    ctx.accounts.quote_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.quote_vault.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
+                        to: ctx.accounts.user_quote_account.to_account_info(),
                         authority: pool.to_account_info(),
                     },
                     signer,
                 ),
-                amount_out,
+                quote_fees,
+                ctx.accounts.quote_mint.decimals,
             )?;
         }
 
-        pool.total_volume += amount_in;
-        pool.total_fees += fee_amount;
+        position.fees_owed_base = 0;
+        position.fees_owed_quote = 0;
 
-        emit!(SwapExecuted {
+        let pool = &mut ctx.accounts.pool;
+        pool.lp_fee_base -= base_fees;
+        pool.lp_fee_quote -= quote_fees;
+        pool.sub_reserves(base_fees, quote_fees)?;
+        pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
+
+        emit!(FeesClaimed {
             pool: pool.key(),
             user: ctx.accounts.user.key(),
-            in_mint: if swap_base_for_quote { pool.base_mint } else { pool.quote_mint },
-            in_amount: amount_in,
-            out_mint: if swap_base_for_quote { pool.quote_mint } else { pool.base_mint },
-            out_amount: amount_out,
-            fee_bps: effective_fee_bps,
-            protocol_fee,
+            position: position.key(),
+            base_amount: base_fees,
+            quote_amount: quote_fees,
         });
 
         Ok(())
     }
 
-    pub fn pause(ctx: Context<PausePool>) -> Result<()> {
-        let pool = &mut ctx.accounts.pool;
-        pool.paused = true;
-        Ok(())
-    }
+    // Moves a position to a new range in one go: pulls all of its liquidity,
+    // optionally folds unclaimed fees back in, swaps the surplus side through
+    // the pool so the value matches the shape, and deposits into the new
+    // range. Whatever cannot be placed is sent back to the holder. Every bin
+    // array touched by the old range, the new range and the swap goes in
+    // remaining_accounts. The active bin has to stay within
+    // `max_active_bin_slippage` bins of `expected_active_bin_id` before and
+    // after the swap, which bounds both the swap price and the new split.
+    pub fn rebalance_position<'info>(
+        ctx: Context<'_, '_, 'info, 'info, RebalancePosition<'info>>,
+        lower_bin_id: i32,
+        upper_bin_id: i32,
+        shape: LiquidityShape,
+        compound_fees: bool,
+        expected_active_bin_id: i32,
+        max_active_bin_slippage: u16,
+    ) -> Result<()> {
+        require!(
+            lower_bin_id <= upper_bin_id
+                && upper_bin_id - lower_bin_id < MAX_BIN_PER_POSITION as i32,
+            ErrorCode::InvalidBinRange
+        );
+        require!(
+            lower_bin_id >= MIN_BIN_ID && upper_bin_id <= MAX_BIN_ID,
+            ErrorCode::InvalidBinId
+        );
 
-    pub fn unpause(ctx: Context<PausePool>) -> Result<()> {
         let pool = &mut ctx.accounts.pool;
-        pool.paused = false;
-        Ok(())
-    }
-}
-
-#[derive(Accounts)]
-pub struct InitializePool<'info> {
-    #[account(
-        init,
-        payer = creator,
-        space = 8 + Pool::LEN,
-        seeds = [b"pool", base_mint.key().as_ref(), quote_mint.key().as_ref(), &bin_step.to_le_bytes()],
-        bump
-    )]
-    pub pool: Account<'info, Pool>,
-
-    #[account(
-        init,
-        payer = creator,
-        token::mint = base_mint,
-        token::authority = pool,
-        seeds = [b"base_vault", pool.key().as_ref()],
-        bump
-    )]
-    pub base_vault: Account<'info, TokenAccount>,
+        require!(
+            pool.status.can_deposit() && pool.status.can_withdraw(),
+            ErrorCode::PoolPaused
+        );
+        pool.require_no_flash_loan()?;
+        pool.require_can_seed(&Clock::get()?, &ctx.accounts.user.key())?;
+        require!(
+            pool.active_bin_id.abs_diff(expected_active_bin_id) <= max_active_bin_slippage as u32,
+            ErrorCode::ActiveBinSlippage
+        );
 
-    #[account(
-        init,
-        payer = creator,
-        token::mint = quote_mint,
-        token::authority = pool,
-        seeds = [b"quote_vault", pool.key().as_ref()],
-        bump
-    )]
-    pub quote_vault: Account<'info, TokenAccount>,
+        let loaders = load_bin_arrays(ctx.remaining_accounts, &pool.key())?;
+        let mut bins = BinArrays::load_mut(&loaders)?;
+        pool.update_rewards(&mut bins, Clock::get()?.unix_timestamp)?;
 
-    /// CHECK: Factory account
-    pub factory: AccountInfo<'info>,
+        let position = &mut ctx.accounts.position;
+        position.update_fees(&mut bins)?;
 
-    pub base_mint: Account<'info, Mint>,
-    pub quote_mint: Account<'info, Mint>,
+        let mut base_amount = 0u64;
+        let mut quote_amount = 0u64;
+        let mut shares_removed = 0u128;
+        for bin_id in position.lower_bin_id..=position.upper_bin_id {
+            let shares = position.shares_mut(bin_id)?;
+            if *shares == 0 {
+                continue;
+            }
 
-    #[account(mut)]
-    pub creator: Signer<'info>,
+            let (bin_base, bin_quote) = bins.bin(bin_id)?.withdraw(*shares)?;
+            shares_removed += *shares;
+            *shares = 0;
+            base_amount += bin_base;
+            quote_amount += bin_quote;
+        }
+        pool.liquidity_base -= base_amount;
+        pool.liquidity_quote -= quote_amount;
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
+        if compound_fees {
+            base_amount = base_amount.checked_add(position.fees_owed_base).ok_or(ErrorCode::MathOverflow)?;
+            quote_amount = quote_amount.checked_add(position.fees_owed_quote).ok_or(ErrorCode::MathOverflow)?;
+            pool.lp_fee_base -= position.fees_owed_base;
+            pool.lp_fee_quote -= position.fees_owed_quote;
+            position.fees_owed_base = 0;
+            position.fees_owed_quote = 0;
+        }
 
-#[derive(Accounts)]
-pub struct AddLiquidity<'info> {
-    #[account(
-        mut,
-        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+        // Split the value between the two sides the way the shape weights them
+        let weights = liquidity_weights(&shape, lower_bin_id, upper_bin_id, pool.active_bin_id)?;
+        let (base_weight, quote_weight) =
+            side_weights((lower_bin_id..=upper_bin_id).zip(weights), pool.active_bin_id);
+        require!(base_weight > 0 || quote_weight > 0, ErrorCode::InvalidDistribution);
 
-    #[account(
-        init_if_needed,
-        payer = user,
-        space = 8 + Position::LEN,
-        seeds = [b"position", pool.key().as_ref(), user.key().as_ref()],
-        bump
-    )]
-    pub position: Account<'info, Position>,
+        let price = get_price_from_id(pool.active_bin_id, pool.bin_step)?;
+        let base_value = to_u64(mul_shr(base_amount as u128, price, Rounding::Down))?;
+        let total_value = base_value as u128 + quote_amount as u128;
+        let target_quote = to_u64(Some(
+            total_value * quote_weight as u128 / (base_weight as u128 + quote_weight as u128),
+        ))?;
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+        let swap = if quote_amount > target_quote {
+            Some((quote_amount - target_quote, false))
+        } else {
+            let base_surplus = get_amount_out(target_quote - quote_amount, price, false)?.min(base_amount);
+            Some((base_surplus, true)).filter(|(amount, _)| *amount > 0)
+        };
 
-    #[account(mut)]
-    pub user_base_account: Account<'info, TokenAccount>,
+        let mut swapped = None;
+        if let Some((amount_in, swap_base_for_quote)) = swap.filter(|(amount, _)| *amount > 0) {
+            let clock = Clock::get()?;
+            let now = clock.unix_timestamp;
+            let base_fee_bps = pool.scheduled_fee_bps(clock.slot, now)?;
+            require!(pool.status.can_swap(), ErrorCode::PoolPaused);
+            pool.require_tradable(&clock)?;
+            ctx.accounts.oracle.update(now, pool.active_bin_id)?;
 
-    #[account(mut)]
-    pub user_quote_account: Account<'info, TokenAccount>,
+            let result = pool.swap(
+                &mut bins,
+                SwapAmount::ExactIn(amount_in),
+                base_fee_bps,
+                0,
+                swap_base_for_quote,
+                None,
+                now,
+            )?;
 
-    #[account(mut)]
-    pub base_vault: Account<'info, TokenAccount>,
+            require!(
+                pool.active_bin_id.abs_diff(expected_active_bin_id) <= max_active_bin_slippage as u32,
+                ErrorCode::ActiveBinSlippage
+            );
 
-    #[account(mut)]
-    pub quote_vault: Account<'info, TokenAccount>,
+            if swap_base_for_quote {
+                base_amount -= result.amount_in;
+                quote_amount += result.amount_out;
+                pool.protocol_fee_base += result.protocol_fee;
+                pool.creator_fee_base += result.creator_fee;
+            } else {
+                quote_amount -= result.amount_in;
+                base_amount += result.amount_out;
+                pool.protocol_fee_quote += result.protocol_fee;
+                pool.creator_fee_quote += result.creator_fee;
+            }
+            pool.total_volume += result.amount_in;
+            pool.total_fees += result.fee;
+            swapped = Some(result);
+        }
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-}
+        // All shares are gone, so this only checkpoints the new range
+        position.lower_bin_id = lower_bin_id;
+        position.upper_bin_id = upper_bin_id;
+        position.update_fees(&mut bins)?;
 
-#[derive(Accounts)]
-pub struct RemoveLiquidity<'info> {
-    #[account(
-        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+        let distribution = distribute_liquidity(
+            &shape,
+            lower_bin_id,
+            upper_bin_id,
+            pool.active_bin_id,
+            base_amount,
+            quote_amount,
+        )?;
 
-    #[account(
-        mut,
-        seeds = [b"position", pool.key().as_ref(), user.key().as_ref()],
-        bump,
-        constraint = position.owner == user.key()
-    )]
-    pub position: Account<'info, Position>,
+        let mut base_deposited = 0u64;
+        let mut quote_deposited = 0u64;
+        let mut shares_added = 0u128;
+        for (bin_id, bin_base, bin_quote) in distribution {
+            if bin_base == 0 && bin_quote == 0 {
+                continue;
+            }
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+            let bin_price = get_price_from_id(bin_id, pool.bin_step)?;
+            let shares = bins.bin(bin_id)?.deposit(bin_base, bin_quote, bin_price)?;
+            if shares == 0 {
+                continue;
+            }
 
-    #[account(mut)]
-    pub user_base_account: Account<'info, TokenAccount>,
+            *position.shares_mut(bin_id)? += shares;
+            shares_added += shares;
+            base_deposited += bin_base;
+            quote_deposited += bin_quote;
+        }
+        drop(bins);
 
-    #[account(mut)]
-    pub user_quote_account: Account<'info, TokenAccount>,
+        position.base_amount = base_deposited;
+        position.quote_amount = quote_deposited;
+        pool.liquidity_base += base_deposited;
+        pool.liquidity_quote += quote_deposited;
+        pool.total_liquidity_shares = pool
+            .total_liquidity_shares
+            .saturating_sub(shares_removed)
+            .checked_add(shares_added)
+            .ok_or(ErrorCode::MathOverflow)?;
 
-    #[account(mut)]
-    pub base_vault: Account<'info, TokenAccount>,
+        let base_refund = base_amount - base_deposited;
+        let quote_refund = quote_amount - quote_deposited;
+
+        let pool = &ctx.accounts.pool;
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        if base_refund > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.base_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.base_vault.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
+                        to: ctx.accounts.user_base_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                base_refund,
+                ctx.accounts.base_mint.decimals,
+            )?;
+        }
+
+        if quote_refund > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.quote_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.quote_vault.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
+                        to: ctx.accounts.user_quote_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                quote_refund,
+                ctx.accounts.quote_mint.decimals,
+            )?;
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        pool.sub_reserves(base_refund, quote_refund)?;
+        pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
+
+        emit!(PositionRebalanced {
+            pool: pool.key(),
+            user: ctx.accounts.user.key(),
+            position: ctx.accounts.position.key(),
+            lower_bin_id,
+            upper_bin_id,
+            base_amount: base_deposited,
+            quote_amount: quote_deposited,
+            swap_amount_in: swapped.map_or(0, |result| result.amount_in),
+            swap_amount_out: swapped.map_or(0, |result| result.amount_out),
+        });
+
+        Ok(())
+    }
+
+    pub fn place_limit_order(
+        ctx: Context<PlaceLimitOrder>,
+        bin_id: i32,
+        amount: u64,
+        is_ask: bool,
+    ) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        require!(pool.status.can_deposit(), ErrorCode::PoolPaused);
+        pool.require_no_flash_loan()?;
+        pool.require_can_seed(&Clock::get()?, &ctx.accounts.user.key())?;
+        // Asks sell base above the active bin and bids sell quote below it,
+        // so an order only fills once the price walks into its bin
+        if is_ask {
+            require!(bin_id > pool.active_bin_id, ErrorCode::InvalidOrderBin);
+        } else {
+            require!(bin_id < pool.active_bin_id, ErrorCode::InvalidOrderBin);
+        }
+        get_price_from_id(bin_id, pool.bin_step)?;
+
+        let mint = ctx.accounts.mint.to_account_info();
+        let amount_received = amount - transfer_fee(&mint, amount)?;
+        require!(amount_received > 0, ErrorCode::ZeroLiquidity);
+
+        token_interface::transfer_checked(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.user_token_account.to_account_info(),
+                    mint,
+                    to: ctx.accounts.vault.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            amount,
+            ctx.accounts.mint.decimals,
+        )?;
+
+        let mut bin_array = ctx.accounts.bin_array.load_mut()?;
+        let side = bin_array.bin_mut(bin_id)?.order_side_mut(is_ask);
+        let (generation, factor_x64) = side.place(amount_received)?;
+
+        let order = &mut ctx.accounts.order;
+        order.owner = ctx.accounts.user.key();
+        order.pool = pool.key();
+        order.bin_id = bin_id;
+        order.is_ask = is_ask;
+        order.amount = amount_received;
+        order.generation = generation;
+        order.factor_x64 = factor_x64;
+        order.converted_claimed = 0;
+        drop(bin_array);
+
+        let pool = &mut ctx.accounts.pool;
+        if is_ask {
+            pool.order_base += amount_received;
+            pool.add_reserves(amount_received, 0)?;
+        } else {
+            pool.order_quote += amount_received;
+            pool.add_reserves(0, amount_received)?;
+        }
+        pool.check_reserve(&mut ctx.accounts.vault)?;
+
+        emit!(LimitOrderPlaced {
+            pool: pool.key(),
+            user: order.owner,
+            order: order.key(),
+            bin_id,
+            is_ask,
+            amount: amount_received,
+        });
+
+        Ok(())
+    }
+
+    pub fn claim_filled_order(ctx: Context<ClaimFilledOrder>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        pool.require_no_flash_loan()?;
+        let order = &mut ctx.accounts.order;
+
+        let mut bin_array = ctx.accounts.bin_array.load_mut()?;
+        let price = get_price_from_id(order.bin_id, pool.bin_step)?;
+        let side = bin_array.bin_mut(order.bin_id)?.order_side_mut(order.is_ask);
+        let converted = order.take_converted(side, price)?;
+        drop(bin_array);
+
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        if converted > 0 {
+            // Asks are paid in quote and bids in base
+            if order.is_ask {
+                token_interface::transfer_checked(
+                    CpiContext::new_with_signer(
+                        ctx.accounts.quote_token_program.to_account_info(),
+                        TransferChecked {
+                            from: ctx.accounts.quote_vault.to_account_info(),
+                            mint: ctx.accounts.quote_mint.to_account_info(),
+                            to: ctx.accounts.user_quote_account.to_account_info(),
+                            authority: pool.to_account_info(),
+                        },
+                        signer,
+                    ),
+                    converted,
+                    ctx.accounts.quote_mint.decimals,
+                )?;
+            } else {
+                token_interface::transfer_checked(
+                    CpiContext::new_with_signer(
+                        ctx.accounts.base_token_program.to_account_info(),
+                        TransferChecked {
+                            from: ctx.accounts.base_vault.to_account_info(),
+                            mint: ctx.accounts.base_mint.to_account_info(),
+                            to: ctx.accounts.user_base_account.to_account_info(),
+                            authority: pool.to_account_info(),
+                        },
+                        signer,
+                    ),
+                    converted,
+                    ctx.accounts.base_mint.decimals,
+                )?;
+            }
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        if order.is_ask {
+            pool.order_quote -= converted;
+            pool.sub_reserves(0, converted)?;
+        } else {
+            pool.order_base -= converted;
+            pool.sub_reserves(converted, 0)?;
+        }
+        pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
+
+        emit!(LimitOrderClaimed {
+            pool: pool.key(),
+            user: ctx.accounts.user.key(),
+            order: order.key(),
+            converted_amount: converted,
+        });
+
+        Ok(())
+    }
+
+    pub fn cancel_limit_order(ctx: Context<CancelLimitOrder>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        pool.require_no_flash_loan()?;
+        let order = &mut ctx.accounts.order;
+
+        let mut bin_array = ctx.accounts.bin_array.load_mut()?;
+        let price = get_price_from_id(order.bin_id, pool.bin_step)?;
+        let side = bin_array.bin_mut(order.bin_id)?.order_side_mut(order.is_ask);
+        let converted = order.take_converted(side, price)?;
+        let remaining = side.withdraw(order.remaining(side)?);
+        drop(bin_array);
+
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let (base_amount, quote_amount) = if order.is_ask {
+            (remaining, converted)
+        } else {
+            (converted, remaining)
+        };
+
+        if base_amount > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.base_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.base_vault.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
+                        to: ctx.accounts.user_base_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                base_amount,
+                ctx.accounts.base_mint.decimals,
+            )?;
+        }
+
+        if quote_amount > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.quote_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.quote_vault.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
+                        to: ctx.accounts.user_quote_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                quote_amount,
+                ctx.accounts.quote_mint.decimals,
+            )?;
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        pool.order_base -= base_amount;
+        pool.order_quote -= quote_amount;
+        pool.sub_reserves(base_amount, quote_amount)?;
+        pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
+
+        emit!(LimitOrderCancelled {
+            pool: pool.key(),
+            user: ctx.accounts.user.key(),
+            order: order.key(),
+            base_amount,
+            quote_amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn swap_exact_in<'info>(
+        ctx: Context<'_, '_, 'info, 'info, Swap<'info>>,
+        amount_in: u64,
+        min_out: u64,
+        swap_base_for_quote: bool,
+        limit_bin_id: Option<i32>,
+    ) -> Result<()> {
+        execute_swap(
+            ctx,
+            SwapAmount::ExactIn(amount_in),
+            min_out,
+            swap_base_for_quote,
+            limit_bin_id,
+        )
+    }
+
+    pub fn swap_exact_out<'info>(
+        ctx: Context<'_, '_, 'info, 'info, Swap<'info>>,
+        amount_out: u64,
+        max_in: u64,
+        swap_base_for_quote: bool,
+        limit_bin_id: Option<i32>,
+    ) -> Result<()> {
+        execute_swap(
+            ctx,
+            SwapAmount::ExactOut(amount_out),
+            max_in,
+            swap_base_for_quote,
+            limit_bin_id,
+        )
+    }
+
+    // Lends vault balances to the caller. A flash_repay for the same pool has
+    // to come later in the same transaction or the whole transaction fails,
+    // and until then the pool takes no swaps, liquidity changes or fee claims.
+    pub fn flash_borrow(
+        ctx: Context<FlashBorrow>,
+        base_amount: u64,
+        quote_amount: u64,
+    ) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        require!(pool.status.can_swap(), ErrorCode::PoolPaused);
+        pool.require_tradable(&Clock::get()?)?;
+        pool.require_no_flash_loan()?;
+        require!(base_amount > 0 || quote_amount > 0, ErrorCode::ZeroLiquidity);
+        // Repayment can only be verified among top-level instructions
+        require!(
+            get_stack_height() == TRANSACTION_LEVEL_STACK_HEIGHT,
+            ErrorCode::FlashLoanNotRepaid
+        );
+
+        let instructions = ctx.accounts.instructions.to_account_info();
+        let current_index = load_current_index_checked(&instructions)? as usize;
+        let mut repaid = false;
+        let mut index = current_index + 1;
+        while let Ok(ix) = load_instruction_at_checked(index, &instructions) {
+            if ix.program_id == crate::ID
+                && ix.data.starts_with(crate::instruction::FlashRepay::DISCRIMINATOR)
+                && ix.accounts.first().map(|meta| meta.pubkey) == Some(pool.key())
+            {
+                repaid = true;
+                break;
+            }
+            index += 1;
+        }
+        require!(repaid, ErrorCode::FlashLoanNotRepaid);
+
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        if base_amount > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.base_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.base_vault.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
+                        to: ctx.accounts.user_base_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                base_amount,
+                ctx.accounts.base_mint.decimals,
+            )?;
+        }
+
+        if quote_amount > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.quote_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.quote_vault.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
+                        to: ctx.accounts.user_quote_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                quote_amount,
+                ctx.accounts.quote_mint.decimals,
+            )?;
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        pool.flash_loan_base = base_amount;
+        pool.flash_loan_quote = quote_amount;
+        pool.flash_loan_bin_id = pool.active_bin_id;
+        pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
+
+        Ok(())
+    }
+
+    // Takes back the outstanding loan plus a fee at the rate a swap would pay.
+    // The fee is split between the protocol and the active bin's LPs.
+    pub fn flash_repay(ctx: Context<FlashRepay>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        let base_amount = pool.flash_loan_base;
+        let quote_amount = pool.flash_loan_quote;
+        require!(base_amount > 0 || quote_amount > 0, ErrorCode::NoFlashLoan);
+
+        let clock = Clock::get()?;
+        let base_fee = pool.flash_loan_fee(base_amount, clock.slot, clock.unix_timestamp)?;
+        let quote_fee = pool.flash_loan_fee(quote_amount, clock.slot, clock.unix_timestamp)?;
+
+        if base_amount > 0 {
+            let owed = base_amount.checked_add(base_fee).ok_or(ErrorCode::MathOverflow)?;
+            token_interface::transfer_checked(
+                CpiContext::new(
+                    ctx.accounts.base_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.user_base_account.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
+                        to: ctx.accounts.base_vault.to_account_info(),
+                        authority: ctx.accounts.user.to_account_info(),
+                    },
+                ),
+                amount_with_transfer_fee(&ctx.accounts.base_mint.to_account_info(), owed)?,
+                ctx.accounts.base_mint.decimals,
+            )?;
+        }
+
+        if quote_amount > 0 {
+            let owed = quote_amount.checked_add(quote_fee).ok_or(ErrorCode::MathOverflow)?;
+            token_interface::transfer_checked(
+                CpiContext::new(
+                    ctx.accounts.quote_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.user_quote_account.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
+                        to: ctx.accounts.quote_vault.to_account_info(),
+                        authority: ctx.accounts.user.to_account_info(),
+                    },
+                ),
+                amount_with_transfer_fee(&ctx.accounts.quote_mint.to_account_info(), owed)?,
+                ctx.accounts.quote_mint.decimals,
+            )?;
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        let base_protocol_fee = (base_fee as u128 * pool.protocol_fee_bps as u128 / BASIS_POINT_MAX) as u64;
+        let quote_protocol_fee = (quote_fee as u128 * pool.protocol_fee_bps as u128 / BASIS_POINT_MAX) as u64;
+
+        // The LP part goes to the bin that was active when the loan was taken;
+        // with no LPs there it goes to the protocol too
+        let mut bin_array = ctx.accounts.bin_array.load_mut()?;
+        let bin = bin_array.bin_mut(pool.flash_loan_bin_id)?;
+        let base_lp_fee = base_fee - base_protocol_fee;
+        let quote_lp_fee = quote_fee - quote_protocol_fee;
+        let (base_lp_fee, quote_lp_fee) = if bin.liquidity_supply > 0 {
+            bin.fee_growth_base_x64 = bin.fee_growth_base_x64.wrapping_add(
+                shl_div(base_lp_fee, bin.liquidity_supply, Rounding::Down).ok_or(ErrorCode::MathOverflow)?,
+            );
+            bin.fee_growth_quote_x64 = bin.fee_growth_quote_x64.wrapping_add(
+                shl_div(quote_lp_fee, bin.liquidity_supply, Rounding::Down).ok_or(ErrorCode::MathOverflow)?,
+            );
+            (base_lp_fee, quote_lp_fee)
+        } else {
+            (0, 0)
+        };
+        drop(bin_array);
+
+        pool.lp_fee_base += base_lp_fee;
+        pool.lp_fee_quote += quote_lp_fee;
+        pool.protocol_fee_base += base_fee - base_lp_fee;
+        pool.protocol_fee_quote += quote_fee - quote_lp_fee;
+        pool.flash_loan_base = 0;
+        pool.flash_loan_quote = 0;
+        pool.add_reserves(base_fee, quote_fee)?;
+        pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
+
+        emit!(FlashLoanRepaid {
+            pool: pool.key(),
+            user: ctx.accounts.user.key(),
+            base_amount,
+            quote_amount,
+            base_fee,
+            quote_fee,
+        });
+
+        Ok(())
+    }
+
+    pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        pool.require_no_flash_loan()?;
+        let base_amount = pool.protocol_fee_base;
+        let quote_amount = pool.protocol_fee_quote;
+
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        if base_amount > 0 {
+            pudl_treasury::cpi::record_fee(
+                CpiContext::new_with_signer(
+                    ctx.accounts.treasury_program.to_account_info(),
+                    pudl_treasury::cpi::accounts::RecordFee {
+                        treasury: ctx.accounts.treasury.to_account_info(),
+                        source: ctx.accounts.base_vault.to_account_info(),
+                        fee_vault: ctx.accounts.base_fee_vault.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
+                        pool_authority: pool.to_account_info(),
+                        token_program: ctx.accounts.base_token_program.to_account_info(),
+                    },
+                    signer,
+                ),
+                base_amount,
+            )?;
+        }
+
+        if quote_amount > 0 {
+            pudl_treasury::cpi::record_fee(
+                CpiContext::new_with_signer(
+                    ctx.accounts.treasury_program.to_account_info(),
+                    pudl_treasury::cpi::accounts::RecordFee {
+                        treasury: ctx.accounts.treasury.to_account_info(),
+                        source: ctx.accounts.quote_vault.to_account_info(),
+                        fee_vault: ctx.accounts.quote_fee_vault.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
+                        pool_authority: pool.to_account_info(),
+                        token_program: ctx.accounts.quote_token_program.to_account_info(),
+                    },
+                    signer,
+                ),
+                quote_amount,
+            )?;
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        pool.protocol_fee_base = 0;
+        pool.protocol_fee_quote = 0;
+        pool.sub_reserves(base_amount, quote_amount)?;
+        pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
+
+        emit!(ProtocolFeesCollected {
+            pool: pool.key(),
+            base_amount,
+            quote_amount,
+        });
+
+        Ok(())
+    }
+
+    // Permissionless. Sweeps only the vault balance above the reserve, which
+    // was sent there directly rather than through the pool, to the treasury
+    // alongside protocol fees. Since the reserve always equals what the pool
+    // owes, this never touches liquidity, LP fees, limit orders or unclaimed
+    // protocol and creator fees.
+    pub fn skim(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        pool.require_no_flash_loan()?;
+        let (base_amount, quote_amount) = pool.skimmable(ctx.accounts.base_vault.amount, ctx.accounts.quote_vault.amount);
+
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        if base_amount > 0 {
+            pudl_treasury::cpi::record_fee(
+                CpiContext::new_with_signer(
+                    ctx.accounts.treasury_program.to_account_info(),
+                    pudl_treasury::cpi::accounts::RecordFee {
+                        treasury: ctx.accounts.treasury.to_account_info(),
+                        source: ctx.accounts.base_vault.to_account_info(),
+                        fee_vault: ctx.accounts.base_fee_vault.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
+                        pool_authority: pool.to_account_info(),
+                        token_program: ctx.accounts.base_token_program.to_account_info(),
+                    },
+                    signer,
+                ),
+                base_amount,
+            )?;
+        }
+
+        if quote_amount > 0 {
+            pudl_treasury::cpi::record_fee(
+                CpiContext::new_with_signer(
+                    ctx.accounts.treasury_program.to_account_info(),
+                    pudl_treasury::cpi::accounts::RecordFee {
+                        treasury: ctx.accounts.treasury.to_account_info(),
+                        source: ctx.accounts.quote_vault.to_account_info(),
+                        fee_vault: ctx.accounts.quote_fee_vault.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
+                        pool_authority: pool.to_account_info(),
+                        token_program: ctx.accounts.quote_token_program.to_account_info(),
+                    },
+                    signer,
+                ),
+                quote_amount,
+            )?;
+        }
+
+        let pool = &ctx.accounts.pool;
+        pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
+
+        emit!(ReservesSkimmed {
+            pool: pool.key(),
+            base_amount,
+            quote_amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn quote_swap<'info>(
+        ctx: Context<'_, '_, 'info, 'info, QuoteSwap<'info>>,
+        amount: SwapAmount,
+        swap_base_for_quote: bool,
+        limit_bin_id: Option<i32>,
+    ) -> Result<SwapQuote> {
+        let mut pool = ctx.accounts.pool.clone().into_inner();
+        require!(pool.status.can_swap(), ErrorCode::PoolPaused);
+
+        let clock = Clock::get()?;
+        pool.require_tradable(&clock)?;
+        let now = clock.unix_timestamp;
+        let base_fee_bps = pool.scheduled_fee_bps(clock.slot, now)?;
+
+        let (in_mint, out_mint) = if swap_base_for_quote {
+            (&ctx.accounts.base_mint, &ctx.accounts.quote_mint)
+        } else {
+            (&ctx.accounts.quote_mint, &ctx.accounts.base_mint)
+        };
+
+        let loaders = load_bin_arrays(ctx.remaining_accounts, &ctx.accounts.pool.key())?;
+        let mut bins = BinArrays::load_copy(&loaders)?;
+        let fee_discount_bps = stake_fee_discount(
+            &ctx.accounts.factory,
+            ctx.accounts.stake_account.as_deref(),
+            now,
+        );
+        let (result, user_amount_in, user_amount_out) = swap_with_transfer_fees(
+            &mut pool,
+            &mut bins,
+            amount,
+            base_fee_bps,
+            fee_discount_bps,
+            swap_base_for_quote,
+            limit_bin_id,
+            now,
+            &in_mint.to_account_info(),
+            &out_mint.to_account_info(),
+        )?;
+
+        Ok(SwapQuote {
+            amount_in: user_amount_in,
+            amount_out: user_amount_out,
+            fee: result.fee,
+            protocol_fee: result.protocol_fee,
+            fee_bps: result.fee_bps(),
+            bins_crossed: result.bins_crossed,
+            active_bin_id: pool.active_bin_id,
+        })
+    }
+
+    // Admin or guardian can restrict the pool to any level short of emergency
+    pub fn pause(ctx: Context<PausePool>, status: PoolStatus) -> Result<()> {
+        require!(
+            status != PoolStatus::Active && status != PoolStatus::Emergency,
+            ErrorCode::InvalidPoolStatus
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        require!(pool.status != PoolStatus::Emergency, ErrorCode::InvalidPoolStatus);
+        pool.status = status;
+
+        emit!(PoolStatusChanged {
+            pool: pool.key(),
+            authority: ctx.accounts.authority.key(),
+            status,
+        });
+
+        Ok(())
+    }
+
+    // Governance sets any status, including back to active and into emergency
+    pub fn unpause(ctx: Context<UnpausePool>, status: PoolStatus) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        pool.status = status;
+
+        emit!(PoolStatusChanged {
+            pool: pool.key(),
+            authority: ctx.accounts.governance.key(),
+            status,
+        });
+
+        Ok(())
+    }
+
+    // Pays a position its share of what is left in the vaults, weighted by
+    // the liquidity shares recorded on the position rather than bin reserves.
+    // Only for emergencies where the bin state cannot be trusted. Protocol and
+    // creator fees and limit order funds stay behind, and order owners can
+    // still cancel and claim on their own.
+    pub fn emergency_withdraw(ctx: Context<EmergencyWithdraw>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        require!(pool.status == PoolStatus::Emergency, ErrorCode::InvalidPoolStatus);
+        pool.require_no_flash_loan()?;
+
+        let position = &mut ctx.accounts.position;
+        let weight = position
+            .liquidity_shares
+            .iter()
+            .try_fold(0u128, |sum, shares| sum.checked_add(*shares))
+            .ok_or(ErrorCode::MathOverflow)?;
+        let (base_amount, quote_amount) = pool.emergency_withdraw_shares(
+            weight,
+            ctx.accounts.base_vault.amount,
+            ctx.accounts.quote_vault.amount,
+        )?;
+
+        let pool = &ctx.accounts.pool;
+
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        if base_amount > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.base_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.base_vault.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
+                        to: ctx.accounts.owner_base_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                base_amount,
+                ctx.accounts.base_mint.decimals,
+            )?;
+        }
+
+        if quote_amount > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.quote_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.quote_vault.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
+                        to: ctx.accounts.owner_quote_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                quote_amount,
+                ctx.accounts.quote_mint.decimals,
+            )?;
+        }
+
+        position.liquidity_shares = [0; MAX_BIN_PER_POSITION];
+        position.fee_debt_base = [0; MAX_BIN_PER_POSITION];
+        position.fee_debt_quote = [0; MAX_BIN_PER_POSITION];
+        position.base_amount = 0;
+        position.quote_amount = 0;
+        position.fees_owed_base = 0;
+        position.fees_owed_quote = 0;
+
+        // Bin reserves no longer match the LP balances, so only the vaults
+        // are checked
+        pool.check_vault(&mut ctx.accounts.base_vault)?;
+        pool.check_vault(&mut ctx.accounts.quote_vault)?;
+
+        emit!(EmergencyWithdrawn {
+            pool: pool.key(),
+            position: position.key(),
+            owner: ctx.accounts.position_token_account.owner,
+            base_amount,
+            quote_amount,
+        });
+
+        Ok(())
+    }
+
+    // Creator operator action: retune the dynamic fee within the same bounds
+    // that apply at pool creation
+    pub fn set_fee_params(ctx: Context<SetFeeParams>, fee_params: DynamicFeeParams) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        require!(pool.dynamic_fee_enabled, ErrorCode::InvalidFeeParams);
+        fee_params.validate(pool.base_fee_bps, ctx.accounts.factory.max_base_fee_bps)?;
+        pool.fee_params = fee_params;
+
+        emit!(FeeParamsUpdated {
+            pool: pool.key(),
+            fee_params,
+        });
+
+        Ok(())
+    }
+
+    // Share of the protocol fee, in bps, paid to the referrer of a swap. Set
+    // by governance, since the protocol pays for it
+    pub fn set_referral_fee(ctx: Context<SetReferralFee>, referral_fee_bps: u16) -> Result<()> {
+        require!(
+            referral_fee_bps <= ctx.accounts.factory.max_referral_fee_bps,
+            ErrorCode::InvalidFeeParams
+        );
+
+        let pool = &mut ctx.accounts.pool;
+        pool.referral_fee_bps = referral_fee_bps;
+
+        emit!(ReferralFeeUpdated {
+            pool: pool.key(),
+            referral_fee_bps,
+        });
+
+        Ok(())
+    }
+
+    // Governance may set any share within the factory bounds; the creator can
+    // only lower it
+    pub fn set_creator_fee(ctx: Context<SetCreatorFee>, creator_fee_bps: u16) -> Result<()> {
+        let factory = &ctx.accounts.factory;
+        let authority = ctx.accounts.authority.key();
+        let pool = &mut ctx.accounts.pool;
+
+        if authority != factory.governance {
+            require_keys_eq!(authority, ctx.accounts.pool_meta.creator, ErrorCode::Unauthorized);
+            require!(creator_fee_bps <= pool.creator_fee_bps, ErrorCode::Unauthorized);
+        }
+        require!(
+            creator_fee_bps >= factory.min_creator_fee_bps
+                && creator_fee_bps <= factory.max_creator_fee_bps
+                && pool.protocol_fee_bps as u128 + creator_fee_bps as u128 <= BASIS_POINT_MAX,
+            ErrorCode::InvalidFeeParams
+        );
+
+        let old = pool.creator_fee_bps;
+        pool.creator_fee_bps = creator_fee_bps;
+
+        emit!(CreatorFeeUpdated {
+            pool: pool.key(),
+            old,
+            new: creator_fee_bps,
+        });
+
+        Ok(())
+    }
+
+    pub fn claim_creator_fees(ctx: Context<ClaimCreatorFees>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        pool.require_no_flash_loan()?;
+        let base_amount = pool.creator_fee_base;
+        let quote_amount = pool.creator_fee_quote;
+
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        if base_amount > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.base_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.base_vault.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
+                        to: ctx.accounts.creator_base_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                base_amount,
+                ctx.accounts.base_mint.decimals,
+            )?;
+        }
+
+        if quote_amount > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.quote_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.quote_vault.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
+                        to: ctx.accounts.creator_quote_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                quote_amount,
+                ctx.accounts.quote_mint.decimals,
+            )?;
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        pool.creator_fee_base = 0;
+        pool.creator_fee_quote = 0;
+        pool.sub_reserves(base_amount, quote_amount)?;
+        pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
+
+        emit!(CreatorFeesClaimed {
+            pool: pool.key(),
+            creator: ctx.accounts.creator.key(),
+            base_amount,
+            quote_amount,
+        });
+
+        Ok(())
+    }
+
+    // The factory keeps its own record of the creator, which creator fees
+    // are paid against, so both change together
+    pub fn set_pool_creator(ctx: Context<SetPoolCreator>, new_creator: Pubkey) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        pudl_factory::cpi::set_pool_creator(
+            CpiContext::new_with_signer(
+                ctx.accounts.factory_program.to_account_info(),
+                pudl_factory::cpi::accounts::SetPoolCreator {
+                    pool_meta: ctx.accounts.pool_meta.to_account_info(),
+                    pool: pool.to_account_info(),
+                    creator: ctx.accounts.creator.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            new_creator,
+        )?;
+
+        let pool = &mut ctx.accounts.pool;
+        let old = pool.creator;
+        pool.creator = new_creator;
+
+        emit!(PoolCreatorUpdated {
+            pool: pool.key(),
+            old,
+            new: new_creator,
+        });
+
+        Ok(())
+    }
+
+    // Opens reward stream `reward_index` with its own vault. Nothing is
+    // emitted until the funder deposits tokens.
+    pub fn initialize_reward(
+        ctx: Context<InitializeReward>,
+        reward_index: u8,
+        reward_duration: u64,
+        funder: Pubkey,
+    ) -> Result<()> {
+        require!((reward_index as usize) < NUM_REWARDS, ErrorCode::InvalidRewardIndex);
+        require!(reward_duration > 0, ErrorCode::InvalidRewardDuration);
+        pudl_factory::validate_mint(&ctx.accounts.reward_mint)?;
+
+        let pool = &mut ctx.accounts.pool;
+        let reward = &mut pool.rewards[reward_index as usize];
+        require!(!reward.initialized(), ErrorCode::RewardAlreadyInitialized);
+
+        let now = Clock::get()?.unix_timestamp;
+        *reward = RewardInfo {
+            mint: ctx.accounts.reward_mint.key(),
+            vault: ctx.accounts.reward_vault.key(),
+            funder,
+            reward_duration,
+            reward_duration_end: now,
+            last_update_time: now,
+            ..Default::default()
+        };
+
+        emit!(RewardInitialized {
+            pool: pool.key(),
+            reward_index,
+            mint: ctx.accounts.reward_mint.key(),
+            funder,
+            reward_duration,
+        });
+
+        Ok(())
+    }
+
+    // Adds `amount` to the stream and restarts it, spreading the unemitted
+    // remainder plus the new tokens over a full duration from now
+    pub fn fund_reward(ctx: Context<FundReward>, reward_index: u8, amount: u64) -> Result<()> {
+        require!(amount > 0, ErrorCode::ZeroLiquidity);
+        let now = Clock::get()?.unix_timestamp;
+
+        let pool = &mut ctx.accounts.pool;
+        let mut bins = BinArrays::load_mut(std::slice::from_ref(&ctx.accounts.bin_array))?;
+        pool.update_rewards(&mut bins, now)?;
+        drop(bins);
+
+        let reward_mint = ctx.accounts.reward_mint.to_account_info();
+        let received = amount - transfer_fee(&reward_mint, amount)?;
+        let reward = pool.reward_mut(reward_index)?;
+        require_keys_eq!(reward.funder, ctx.accounts.funder.key(), ErrorCode::Unauthorized);
+        reward.restart(now, received)?;
+        let reward_rate_x64 = reward.reward_rate_x64;
+        let reward_duration_end = reward.reward_duration_end;
+
+        token_interface::transfer_checked(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.funder_token_account.to_account_info(),
+                    mint: reward_mint,
+                    to: ctx.accounts.reward_vault.to_account_info(),
+                    authority: ctx.accounts.funder.to_account_info(),
+                },
+            ),
+            amount,
+            ctx.accounts.reward_mint.decimals,
+        )?;
+
+        emit!(RewardFunded {
+            pool: pool.key(),
+            reward_index,
+            funder: ctx.accounts.funder.key(),
+            amount: received,
+            reward_rate_x64,
+            reward_duration_end,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_reward_funder(
+        ctx: Context<ManageReward>,
+        reward_index: u8,
+        new_funder: Pubkey,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let reward = pool.reward_mut(reward_index)?;
+        let old = reward.funder;
+        reward.funder = new_funder;
+
+        emit!(RewardFunderUpdated {
+            pool: pool.key(),
+            reward_index,
+            old,
+            new: new_funder,
+        });
+
+        Ok(())
+    }
+
+    // Changes how long a funding lasts. A running stream is re-spread over the
+    // new duration from now, which is how its rate is adjusted.
+    pub fn set_reward_duration(
+        ctx: Context<SetRewardDuration>,
+        reward_index: u8,
+        reward_duration: u64,
+    ) -> Result<()> {
+        require!(reward_duration > 0, ErrorCode::InvalidRewardDuration);
+        let now = Clock::get()?.unix_timestamp;
+
+        let pool = &mut ctx.accounts.pool;
+        let mut bins = BinArrays::load_mut(std::slice::from_ref(&ctx.accounts.bin_array))?;
+        pool.update_rewards(&mut bins, now)?;
+        drop(bins);
+
+        let reward = pool.reward_mut(reward_index)?;
+        reward.reward_duration = reward_duration;
+        if now < reward.reward_duration_end {
+            reward.restart(now, 0)?;
+        }
+        let reward_rate_x64 = reward.reward_rate_x64;
+        let reward_duration_end = reward.reward_duration_end;
+
+        emit!(RewardDurationUpdated {
+            pool: pool.key(),
+            reward_index,
+            reward_duration,
+            reward_rate_x64,
+            reward_duration_end,
+        });
+
+        Ok(())
+    }
+
+    pub fn claim_reward(ctx: Context<ClaimReward>, reward_index: u8) -> Result<()> {
+        require!(ctx.accounts.pool.status.can_withdraw(), ErrorCode::PoolPaused);
+        let pool = &mut ctx.accounts.pool;
+        pool.reward_mut(reward_index)?;
+
+        let position = &mut ctx.accounts.position;
+        let loaders = bin_array_pair(&ctx.accounts.bin_array_lower, &ctx.accounts.bin_array_upper);
+        let mut bins = BinArrays::load_mut(&loaders)?;
+        pool.update_rewards(&mut bins, Clock::get()?.unix_timestamp)?;
+        position.update_fees(&mut bins)?;
+        drop(bins);
+
+        let amount = position.rewards_owed[reward_index as usize];
+        position.rewards_owed[reward_index as usize] = 0;
+
+        if amount > 0 {
+            let seeds = &[
+                b"pool",
+                pool.base_mint.as_ref(),
+                pool.quote_mint.as_ref(),
+                &pool.bin_step.to_le_bytes(),
+                &[pool.bump],
+            ];
+            let signer = &[&seeds[..]];
+
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.reward_vault.to_account_info(),
+                        mint: ctx.accounts.reward_mint.to_account_info(),
+                        to: ctx.accounts.user_token_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                amount,
+                ctx.accounts.reward_mint.decimals,
+            )?;
+        }
+
+        emit!(RewardClaimed {
+            pool: pool.key(),
+            user: ctx.accounts.user.key(),
+            position: position.key(),
+            reward_index,
+            amount,
+        });
+
+        Ok(())
+    }
+
+    // Returns to the funder what was emitted while the active bin had no
+    // liquidity to earn it
+    pub fn withdraw_undistributed_reward(
+        ctx: Context<WithdrawUndistributedReward>,
+        reward_index: u8,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let reward = pool.reward_mut(reward_index)?;
+        require_keys_eq!(reward.funder, ctx.accounts.funder.key(), ErrorCode::Unauthorized);
+
+        let amount = to_u64(Some(reward.undistributed_x64 >> SCALE_OFFSET))?;
+        reward.undistributed_x64 -= (amount as u128) << SCALE_OFFSET;
+
+        if amount > 0 {
+            let seeds = &[
+                b"pool",
+                pool.base_mint.as_ref(),
+                pool.quote_mint.as_ref(),
+                &pool.bin_step.to_le_bytes(),
+                &[pool.bump],
+            ];
+            let signer = &[&seeds[..]];
+
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.reward_vault.to_account_info(),
+                        mint: ctx.accounts.reward_mint.to_account_info(),
+                        to: ctx.accounts.funder_token_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    signer,
+                ),
+                amount,
+                ctx.accounts.reward_mint.decimals,
+            )?;
+        }
+
+        emit!(UndistributedRewardWithdrawn {
+            pool: pool.key(),
+            reward_index,
+            funder: ctx.accounts.funder.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    // Sets up the pre-launch vault. Until it is filled, trading stays closed
+    // even after activation. The fill never buys above `max_price_bin_id`.
+    pub fn initialize_alpha_vault(
+        ctx: Context<InitializeAlphaVault>,
+        merkle_root: [u8; 32],
+        max_buying_cap: u64,
+        max_price_bin_id: i32,
+        vesting_duration: u64,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        require!(!pool.is_activated(&Clock::get()?), ErrorCode::PoolAlreadyActivated);
+        require!(max_buying_cap > 0, ErrorCode::ZeroLiquidity);
+        require!(
+            max_price_bin_id >= pool.active_bin_id && max_price_bin_id <= MAX_BIN_ID,
+            ErrorCode::InvalidLimitBin
+        );
+        pool.alpha_vault_pending = true;
+
+        let vault = &mut ctx.accounts.alpha_vault;
+        vault.pool = pool.key();
+        vault.merkle_root = merkle_root;
+        vault.quote_account = ctx.accounts.alpha_quote_account.key();
+        vault.base_account = ctx.accounts.alpha_base_account.key();
+        vault.max_buying_cap = max_buying_cap;
+        vault.max_price_bin_id = max_price_bin_id;
+        vault.total_deposited = 0;
+        vault.quote_spent = 0;
+        vault.base_bought = 0;
+        vault.vesting_start = 0;
+        vault.vesting_duration = vesting_duration;
+        vault.filled = false;
+        vault.bump = ctx.bumps.alpha_vault;
+
+        emit!(AlphaVaultInitialized {
+            pool: pool.key(),
+            alpha_vault: vault.key(),
+            max_buying_cap,
+            max_price_bin_id,
+            vesting_duration,
+        });
+
+        Ok(())
+    }
+
+    // Opens a deposit escrow for a whitelisted wallet, capped at the amount
+    // committed to in its merkle leaf
+    pub fn create_alpha_escrow(
+        ctx: Context<CreateAlphaEscrow>,
+        max_cap: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let owner = ctx.accounts.owner.key();
+        require!(
+            verify_whitelist(&ctx.accounts.alpha_vault.merkle_root, &owner, max_cap, &proof),
+            ErrorCode::NotWhitelisted
+        );
+
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.alpha_vault = ctx.accounts.alpha_vault.key();
+        escrow.owner = owner;
+        escrow.max_cap = max_cap;
+        escrow.deposited = 0;
+        escrow.claimed_base = 0;
+        escrow.refunded = false;
+        escrow.bump = ctx.bumps.escrow;
+
+        Ok(())
+    }
+
+    pub fn deposit_alpha_vault(ctx: Context<DepositAlphaVault>, amount: u64) -> Result<()> {
+        require!(!ctx.accounts.pool.is_activated(&Clock::get()?), ErrorCode::PoolAlreadyActivated);
+
+        let quote_mint = ctx.accounts.quote_mint.to_account_info();
+        let received = amount - transfer_fee(&quote_mint, amount)?;
+        require!(received > 0, ErrorCode::ZeroLiquidity);
+
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.deposited = escrow.deposited.checked_add(received).ok_or(ErrorCode::MathOverflow)?;
+        require!(escrow.deposited <= escrow.max_cap, ErrorCode::DepositCapExceeded);
+
+        let vault = &mut ctx.accounts.alpha_vault;
+        vault.total_deposited = vault.total_deposited.checked_add(received).ok_or(ErrorCode::MathOverflow)?;
+
+        token_interface::transfer_checked(
+            CpiContext::new(
+                ctx.accounts.quote_token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.owner_quote_account.to_account_info(),
+                    mint: quote_mint,
+                    to: ctx.accounts.alpha_quote_account.to_account_info(),
+                    authority: ctx.accounts.owner.to_account_info(),
+                },
+            ),
+            amount,
+            ctx.accounts.quote_mint.decimals,
+        )?;
+
+        emit!(AlphaVaultDeposited {
+            alpha_vault: vault.key(),
+            owner: ctx.accounts.owner.key(),
+            amount: received,
+            total_deposited: vault.total_deposited,
+        });
+
+        Ok(())
+    }
+
+    // Permissionless crank run at activation. Buys base with the pooled quote
+    // (up to the cap) in one swap, so every depositor pays the same average
+    // price, then opens the pool to everyone else. The swap has to spend the
+    // whole amount or buy out every bin up to the price limit. Waits while
+    // swaps are paused. If the pool is already past the limit, nothing is
+    // bought and depositors claim their quote back.
+    pub fn fill_alpha_vault<'info>(
+        ctx: Context<'_, '_, 'info, 'info, FillAlphaVault<'info>>,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        pool.require_no_flash_loan()?;
+
+        require!(pool.status.can_swap(), ErrorCode::PoolPaused);
+
+        let clock = Clock::get()?;
+        require!(pool.is_activated(&clock), ErrorCode::PoolNotActivated);
+        let vault = &mut ctx.accounts.alpha_vault;
+        require!(!vault.filled, ErrorCode::AlphaVaultAlreadyFilled);
+
+        let amount_in = vault.total_deposited.min(vault.max_buying_cap);
+        let mut quote_spent = 0;
+        let mut base_bought = 0;
+        if amount_in > 0 && pool.active_bin_id <= vault.max_price_bin_id {
+            let now = clock.unix_timestamp;
+            let base_fee_bps = pool.scheduled_fee_bps(clock.slot, now)?;
+            ctx.accounts.oracle.update(now, pool.active_bin_id)?;
+
+            let loaders = load_bin_arrays(ctx.remaining_accounts, &pool.key())?;
+            let mut bins = BinArrays::load_mut(&loaders)?;
+            let (result, user_amount_in, user_amount_out) = swap_with_transfer_fees(
+                pool,
+                &mut bins,
+                SwapAmount::ExactIn(amount_in),
+                base_fee_bps,
+                0,
+                false,
+                Some(vault.max_price_bin_id),
+                now,
+                &ctx.accounts.quote_mint.to_account_info(),
+                &ctx.accounts.base_mint.to_account_info(),
+            )?;
+            // Stopping short is only fine at an emptied limit bin; anywhere
+            // else the caller left bin arrays out
+            if user_amount_in < amount_in {
+                require!(
+                    pool.active_bin_id == vault.max_price_bin_id
+                        && bins.bin(pool.active_bin_id)?.is_empty(false),
+                    ErrorCode::AlphaVaultUnderfilled
+                );
+            }
+            drop(bins);
+
+            let pool_key = pool.key();
+            let vault_seeds = &[b"alpha_vault", pool_key.as_ref(), &[vault.bump]];
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.quote_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.alpha_quote_account.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
+                        to: ctx.accounts.quote_vault.to_account_info(),
+                        authority: vault.to_account_info(),
+                    },
+                    &[&vault_seeds[..]],
+                ),
+                user_amount_in,
+                ctx.accounts.quote_mint.decimals,
+            )?;
+
+            let pool_seeds = &[
+                b"pool",
+                pool.base_mint.as_ref(),
+                pool.quote_mint.as_ref(),
+                &pool.bin_step.to_le_bytes(),
+                &[pool.bump],
+            ];
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.base_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.base_vault.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
+                        to: ctx.accounts.alpha_base_account.to_account_info(),
+                        authority: pool.to_account_info(),
+                    },
+                    &[&pool_seeds[..]],
+                ),
+                result.amount_out,
+                ctx.accounts.base_mint.decimals,
+            )?;
+
+            pool.total_volume += result.amount_in;
+            pool.total_fees += result.fee;
+            pool.protocol_fee_quote += result.protocol_fee;
+            pool.creator_fee_quote += result.creator_fee;
+            pool.add_reserves(0, result.amount_in)?;
+            pool.sub_reserves(result.amount_out, 0)?;
+            pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
+            quote_spent = user_amount_in;
+            base_bought = user_amount_out;
+        }
+
+        vault.quote_spent = quote_spent;
+        vault.base_bought = base_bought;
+        vault.vesting_start = pool.activation_type.current(&clock);
+        vault.filled = true;
+        pool.alpha_vault_pending = false;
+
+        emit!(AlphaVaultFilled {
+            pool: pool.key(),
+            alpha_vault: vault.key(),
+            quote_spent,
+            base_bought,
+        });
+
+        Ok(())
+    }
+
+    // Pays out the depositor's vested share of the base bought and, on the
+    // first claim, their share of any quote the fill did not spend
+    pub fn claim_alpha_vault(ctx: Context<ClaimAlphaVault>) -> Result<()> {
+        let vault = &ctx.accounts.alpha_vault;
+        require!(vault.filled, ErrorCode::AlphaVaultNotFilled);
+
+        let escrow = &mut ctx.accounts.escrow;
+        let now_point = ctx.accounts.pool.activation_type.current(&Clock::get()?);
+        let base_amount = vault.vested_base(escrow, now_point)? - escrow.claimed_base;
+        escrow.claimed_base += base_amount;
+
+        let quote_refund = if escrow.refunded {
+            0
+        } else {
+            escrow.refunded = true;
+            vault.quote_refund(escrow)?
+        };
+
+        let pool_key = vault.pool;
+        let vault_seeds = &[b"alpha_vault", pool_key.as_ref(), &[vault.bump]];
+        let signer = &[&vault_seeds[..]];
+
+        if base_amount > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.base_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.alpha_base_account.to_account_info(),
+                        mint: ctx.accounts.base_mint.to_account_info(),
+                        to: ctx.accounts.owner_base_account.to_account_info(),
+                        authority: vault.to_account_info(),
+                    },
+                    signer,
+                ),
+                base_amount,
+                ctx.accounts.base_mint.decimals,
+            )?;
+        }
+
+        if quote_refund > 0 {
+            token_interface::transfer_checked(
+                CpiContext::new_with_signer(
+                    ctx.accounts.quote_token_program.to_account_info(),
+                    TransferChecked {
+                        from: ctx.accounts.alpha_quote_account.to_account_info(),
+                        mint: ctx.accounts.quote_mint.to_account_info(),
+                        to: ctx.accounts.owner_quote_account.to_account_info(),
+                        authority: vault.to_account_info(),
+                    },
+                    signer,
+                ),
+                quote_refund,
+                ctx.accounts.quote_mint.decimals,
+            )?;
+        }
+
+        emit!(AlphaVaultClaimed {
+            alpha_vault: vault.key(),
+            owner: ctx.accounts.owner.key(),
+            base_amount,
+            quote_refund,
+        });
+
+        Ok(())
+    }
+}
+
+// Shared by the exact-in and exact-out swaps. `other_amount_threshold` is
+// the minimum output for exact-in and the maximum input for exact-out. With
+// a `limit_bin_id` the swap may fill partially, and only the input actually
+// consumed is taken from the user.
+fn execute_swap<'info>(
+    ctx: Context<'_, '_, 'info, 'info, Swap<'info>>,
+    amount: SwapAmount,
+    other_amount_threshold: u64,
+    swap_base_for_quote: bool,
+    limit_bin_id: Option<i32>,
+) -> Result<()> {
+    let pool = &mut ctx.accounts.pool;
+    require!(pool.status.can_swap(), ErrorCode::PoolPaused);
+    pool.require_no_flash_loan()?;
+
+    let clock = Clock::get()?;
+    pool.require_tradable(&clock)?;
+    let now = clock.unix_timestamp;
+    let base_fee_bps = pool.scheduled_fee_bps(clock.slot, now)?;
+
+    // Record the bin that was active up to now before this swap moves it
+    ctx.accounts.oracle.update(now, pool.active_bin_id)?;
+
+    let (in_mint, out_mint) = if swap_base_for_quote {
+        (&ctx.accounts.base_mint, &ctx.accounts.quote_mint)
+    } else {
+        (&ctx.accounts.quote_mint, &ctx.accounts.base_mint)
+    };
+
+    let loaders = load_bin_arrays(ctx.remaining_accounts, &pool.key())?;
+    let mut bins = BinArrays::load_mut(&loaders)?;
+    let fee_discount_bps = stake_fee_discount(
+        &ctx.accounts.factory,
+        ctx.accounts.stake_account.as_deref(),
+        now,
+    );
+    let (result, user_amount_in, user_amount_out) = swap_with_transfer_fees(
+        pool,
+        &mut bins,
+        amount,
+        base_fee_bps,
+        fee_discount_bps,
+        swap_base_for_quote,
+        limit_bin_id,
+        now,
+        &in_mint.to_account_info(),
+        &out_mint.to_account_info(),
+    )?;
+    drop(bins);
+
+    // Thresholds apply to what leaves and reaches the user's wallet
+    let partial_fill = limit_bin_id.is_some();
+    match amount {
+        SwapAmount::ExactIn(amount_in) => {
+            require!(
+                user_amount_in == amount_in || (partial_fill && result.amount_in > 0),
+                ErrorCode::InsufficientLiquidity
+            );
+            require!(user_amount_out >= other_amount_threshold, ErrorCode::SlippageExceeded);
+        }
+        SwapAmount::ExactOut(amount_out) => {
+            require!(
+                user_amount_out >= amount_out || (partial_fill && user_amount_out > 0),
+                ErrorCode::InsufficientLiquidity
+            );
+            require!(user_amount_in <= other_amount_threshold, ErrorCode::SlippageExceeded);
+        }
+    }
+
+    let amount_in = result.amount_in;
+    let amount_out = result.amount_out;
+    let fee_amount = result.fee;
+    let protocol_fee = result.protocol_fee;
+    let effective_fee_bps = result.fee_bps();
+
+    if swap_base_for_quote {
+        token_interface::transfer_checked(
+            CpiContext::new(
+                ctx.accounts.base_token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.user_base_account.to_account_info(),
+                    mint: ctx.accounts.base_mint.to_account_info(),
+                    to: ctx.accounts.base_vault.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            user_amount_in,
+            ctx.accounts.base_mint.decimals,
+        )?;
+
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
+                ctx.accounts.quote_token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.quote_vault.to_account_info(),
+                    mint: ctx.accounts.quote_mint.to_account_info(),
+                    to: ctx.accounts.user_quote_account.to_account_info(),
+                    authority: pool.to_account_info(),
+                },
+                signer,
+            ),
+            amount_out,
+            ctx.accounts.quote_mint.decimals,
+        )?;
+    } else {
+        token_interface::transfer_checked(
+            CpiContext::new(
+                ctx.accounts.quote_token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.user_quote_account.to_account_info(),
+                    mint: ctx.accounts.quote_mint.to_account_info(),
+                    to: ctx.accounts.quote_vault.to_account_info(),
+                    authority: ctx.accounts.user.to_account_info(),
+                },
+            ),
+            user_amount_in,
+            ctx.accounts.quote_mint.decimals,
+        )?;
+
+        let seeds = &[
+            b"pool",
+            pool.base_mint.as_ref(),
+            pool.quote_mint.as_ref(),
+            &pool.bin_step.to_le_bytes(),
+            &[pool.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        token_interface::transfer_checked(
+            CpiContext::new_with_signer(
+                ctx.accounts.base_token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.base_vault.to_account_info(),
+                    mint: ctx.accounts.base_mint.to_account_info(),
+                    to: ctx.accounts.user_base_account.to_account_info(),
+                    authority: pool.to_account_info(),
+                },
+                signer,
+            ),
+            amount_out,
+            ctx.accounts.base_mint.decimals,
+        )?;
+    }
+
+    // The referrer's cut comes out of the protocol fee already in the vault
+    let referral_fee = match &ctx.accounts.referrer_token_account {
+        Some(referrer) => {
+            let (vault, mint, token_program) = if swap_base_for_quote {
+                (&ctx.accounts.base_vault, &ctx.accounts.base_mint, &ctx.accounts.base_token_program)
+            } else {
+                (&ctx.accounts.quote_vault, &ctx.accounts.quote_mint, &ctx.accounts.quote_token_program)
+            };
+            require_keys_eq!(referrer.mint, mint.key(), ErrorCode::InvalidReferrer);
+            // Swappers cannot refer themselves into a fee rebate
+            require_keys_neq!(referrer.owner, ctx.accounts.user.key(), ErrorCode::InvalidReferrer);
+
+            // The factory may have lowered its cap since the pool set its rate
+            let referral_fee_bps = pool.referral_fee_bps.min(ctx.accounts.factory.max_referral_fee_bps);
+            let referral_fee = (protocol_fee as u128 * referral_fee_bps as u128 / BASIS_POINT_MAX) as u64;
+            if referral_fee > 0 {
+                let seeds = &[
+                    b"pool",
+                    pool.base_mint.as_ref(),
+                    pool.quote_mint.as_ref(),
+                    &pool.bin_step.to_le_bytes(),
+                    &[pool.bump],
+                ];
+                token_interface::transfer_checked(
+                    CpiContext::new_with_signer(
+                        token_program.to_account_info(),
+                        TransferChecked {
+                            from: vault.to_account_info(),
+                            mint: mint.to_account_info(),
+                            to: referrer.to_account_info(),
+                            authority: pool.to_account_info(),
+                        },
+                        &[&seeds[..]],
+                    ),
+                    referral_fee,
+                    mint.decimals,
+                )?;
+            }
+            referral_fee
+        }
+        None => 0,
+    };
+    let protocol_fee = protocol_fee - referral_fee;
+
+    pool.total_volume += amount_in;
+    pool.total_fees += fee_amount;
+    if swap_base_for_quote {
+        pool.protocol_fee_base += protocol_fee;
+        pool.creator_fee_base += result.creator_fee;
+        pool.add_reserves(amount_in, 0)?;
+        pool.sub_reserves(referral_fee, amount_out)?;
+    } else {
+        pool.protocol_fee_quote += protocol_fee;
+        pool.creator_fee_quote += result.creator_fee;
+        pool.add_reserves(0, amount_in)?;
+        pool.sub_reserves(amount_out, referral_fee)?;
+    }
+    pool.check_reserves(&mut ctx.accounts.base_vault, &mut ctx.accounts.quote_vault)?;
+
+    emit!(SwapExecuted {
+        pool: pool.key(),
+        user: ctx.accounts.user.key(),
+        in_mint: if swap_base_for_quote { pool.base_mint } else { pool.quote_mint },
+        in_amount: user_amount_in,
+        out_mint: if swap_base_for_quote { pool.quote_mint } else { pool.base_mint },
+        out_amount: user_amount_out,
+        fee_bps: effective_fee_bps,
+        protocol_fee,
+        referrer: ctx.accounts.referrer_token_account.as_ref().map(|referrer| referrer.key()),
+        referral_fee,
+        creator_fee: result.creator_fee,
+    });
+
+    Ok(())
+}
+
+// Runs the bin walk on amounts net of token-2022 transfer fees, so the bins
+// only see what actually reaches or leaves the vaults. Also returns what the
+// user sends and what they end up receiving.
+#[allow(clippy::too_many_arguments)]
+fn swap_with_transfer_fees<S: BinStore>(
+    pool: &mut Pool,
+    bins: &mut S,
+    amount: SwapAmount,
+    base_fee_bps: u16,
+    fee_discount_bps: u16,
+    swap_base_for_quote: bool,
+    limit_bin_id: Option<i32>,
+    now: i64,
+    in_mint: &AccountInfo,
+    out_mint: &AccountInfo,
+) -> Result<(SwapResult, u64, u64)> {
+    let bin_amount = match amount {
+        SwapAmount::ExactIn(amount_in) => {
+            SwapAmount::ExactIn(amount_in - transfer_fee(in_mint, amount_in)?)
+        }
+        SwapAmount::ExactOut(amount_out) => {
+            SwapAmount::ExactOut(amount_with_transfer_fee(out_mint, amount_out)?)
+        }
+    };
+
+    let result = pool.swap(
+        bins,
+        bin_amount,
+        base_fee_bps,
+        fee_discount_bps,
+        swap_base_for_quote,
+        limit_bin_id,
+        now,
+    )?;
+
+    let user_amount_in = match (amount, bin_amount) {
+        (SwapAmount::ExactIn(amount_in), SwapAmount::ExactIn(net_in))
+            if result.amount_in == net_in =>
+        {
+            amount_in
+        }
+        _ => amount_with_transfer_fee(in_mint, result.amount_in)?,
+    };
+    let user_amount_out = result.amount_out - transfer_fee(out_mint, result.amount_out)?;
+
+    Ok((result, user_amount_in, user_amount_out))
+}
+
+// A stake has to sit this long before it earns a discount, so it cannot be
+// opened and closed around a swap
+pub const MIN_STAKE_AGE: i64 = 86_400;
+
+// Discount the factory grants to the swapper's PUDL staking tier
+fn stake_fee_discount(
+    factory: &pudl_factory::Factory,
+    stake_account: Option<&Account<pudl_staking::StakeAccount>>,
+    now: i64,
+) -> u16 {
+    stake_account
+        .filter(|stake| now - stake.last_update >= MIN_STAKE_AGE)
+        .map_or(0, |stake| factory.fee_discount_bps(stake.tier))
+}
+
+fn transfer_fee_config(mint: &AccountInfo) -> Result<Option<TransferFeeConfig>> {
+    if *mint.owner != Token2022::id() {
+        return Ok(None);
+    }
+
+    let data = mint.try_borrow_data()?;
+    let state = StateWithExtensions::<MintState>::unpack(&data)?;
+    Ok(state.get_extension::<TransferFeeConfig>().ok().copied())
+}
+
+// Fee withheld by token-2022 when `amount` is transferred
+pub fn transfer_fee(mint: &AccountInfo, amount: u64) -> Result<u64> {
+    let Some(config) = transfer_fee_config(mint)? else {
+        return Ok(0);
+    };
+
+    config
+        .calculate_epoch_fee(Clock::get()?.epoch, amount)
+        .ok_or_else(|| error!(ErrorCode::MathOverflow))
+}
+
+// Amount to transfer so that `amount` arrives after the transfer fee
+pub fn amount_with_transfer_fee(mint: &AccountInfo, amount: u64) -> Result<u64> {
+    let Some(config) = transfer_fee_config(mint)? else {
+        return Ok(amount);
+    };
+    if amount == 0 {
+        return Ok(0);
+    }
+
+    let fee = config
+        .calculate_inverse_epoch_fee(Clock::get()?.epoch, amount)
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok(amount.checked_add(fee).ok_or(ErrorCode::MathOverflow)?)
+}
+
+#[derive(Accounts)]
+#[instruction(base_fee_bps: u16, protocol_fee_bps: u16, bin_step: u16)]
+pub struct InitializePool<'info> {
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + Pool::LEN,
+        seeds = [b"pool", base_mint.key().as_ref(), quote_mint.key().as_ref(), &bin_step.to_le_bytes()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init,
+        payer = creator,
+        space = Oracle::space(DEFAULT_ORACLE_LENGTH),
+        seeds = [b"oracle", pool.key().as_ref()],
+        bump
+    )]
+    pub oracle: Box<Account<'info, Oracle>>,
+
+    #[account(
+        init,
+        payer = creator,
+        token::mint = base_mint,
+        token::authority = pool,
+        token::token_program = base_token_program,
+        seeds = [b"base_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = creator,
+        token::mint = quote_mint,
+        token::authority = pool,
+        token::token_program = quote_token_program,
+        seeds = [b"quote_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(seeds = [b"factory"], bump = factory.bump, seeds::program = pudl_factory::ID)]
+    pub factory: Account<'info, pudl_factory::Factory>,
+
+    #[account(
+        seeds = [b"pool", base_mint.key().as_ref(), quote_mint.key().as_ref(), &bin_step.to_le_bytes()],
+        bump = pool_meta.bump,
+        seeds::program = pudl_factory::ID,
+        constraint = pool_meta.creator == creator.key()
+    )]
+    pub pool_meta: Account<'info, pudl_factory::PoolMeta>,
+
+    #[account(mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+#[instruction(length_to_add: u16)]
+pub struct IncreaseOracleLength<'info> {
+    #[account(
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"oracle", pool.key().as_ref()],
+        bump = oracle.bump,
+        realloc = Oracle::space(oracle.observations.len() + length_to_add as usize),
+        realloc::payer = payer,
+        realloc::zero = false
+    )]
+    pub oracle: Box<Account<'info, Oracle>>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct GetTwap<'info> {
+    #[account(
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(seeds = [b"oracle", pool.key().as_ref()], bump = oracle.bump)]
+    pub oracle: Box<Account<'info, Oracle>>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: i64)]
+pub struct InitializeBinArray<'info> {
+    #[account(
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + BinArray::LEN,
+        seeds = [b"bin_array", pool.key().as_ref(), &index.to_le_bytes()],
+        bump
+    )]
+    pub bin_array: AccountLoader<'info, BinArray>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OpenPosition<'info> {
+    #[account(
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + Position::LEN
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        init,
+        payer = user,
+        seeds = [b"position_mint", position.key().as_ref()],
+        bump,
+        mint::decimals = 0,
+        mint::authority = pool,
+        mint::token_program = token_2022_program,
+        extensions::metadata_pointer::authority = pool,
+        extensions::metadata_pointer::metadata_address = position_mint,
+        extensions::close_authority::authority = pool
+    )]
+    pub position_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init,
+        payer = user,
+        associated_token::mint = position_mint,
+        associated_token::authority = user,
+        associated_token::token_program = token_2022_program
+    )]
+    pub position_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub token_2022_program: Program<'info, Token2022>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        close = user,
+        constraint = position.pool == pool.key()
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(mut, address = position.nft_mint)]
+    pub position_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        token::mint = position_mint,
+        token::authority = user,
+        token::token_program = token_2022_program
+    )]
+    pub position_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub token_2022_program: Program<'info, Token2022>,
+}
+
+#[derive(Accounts)]
+pub struct AddLiquidity<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, constraint = position.pool == pool.key())]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        constraint = position_token_account.mint == position.nft_mint,
+        constraint = position_token_account.owner == user.key(),
+        constraint = position_token_account.amount == 1 @ ErrorCode::NotPositionHolder
+    )]
+    pub position_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(position.lower_bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array_lower: AccountLoader<'info, BinArray>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(position.upper_bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array_upper: AccountLoader<'info, BinArray>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveLiquidity<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, constraint = position.pool == pool.key())]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        constraint = position_token_account.mint == position.nft_mint,
+        constraint = position_token_account.owner == user.key(),
+        constraint = position_token_account.amount == 1 @ ErrorCode::NotPositionHolder
+    )]
+    pub position_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(position.lower_bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array_lower: AccountLoader<'info, BinArray>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(position.upper_bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array_upper: AccountLoader<'info, BinArray>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimFees<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, constraint = position.pool == pool.key())]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        constraint = position_token_account.mint == position.nft_mint,
+        constraint = position_token_account.owner == user.key(),
+        constraint = position_token_account.amount == 1 @ ErrorCode::NotPositionHolder
+    )]
+    pub position_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(position.lower_bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array_lower: AccountLoader<'info, BinArray>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(position.upper_bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array_upper: AccountLoader<'info, BinArray>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RebalancePosition<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, seeds = [b"oracle", pool.key().as_ref()], bump = oracle.bump)]
+    pub oracle: Box<Account<'info, Oracle>>,
+
+    #[account(mut, constraint = position.pool == pool.key())]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        constraint = position_token_account.mint == position.nft_mint,
+        constraint = position_token_account.owner == user.key(),
+        constraint = position_token_account.amount == 1 @ ErrorCode::NotPositionHolder
+    )]
+    pub position_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(bin_id: i32, amount: u64, is_ask: bool)]
+pub struct PlaceLimitOrder<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + LimitOrder::LEN
+    )]
+    pub order: Box<Account<'info, LimitOrder>>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array: AccountLoader<'info, BinArray>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut, token::mint = mint)]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = vault.key() == if is_ask { pool.base_vault } else { pool.quote_vault }
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        constraint = mint.key() == if is_ask { pool.base_mint } else { pool.quote_mint },
+        mint::token_program = token_program
+    )]
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimFilledOrder<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        constraint = order.pool == pool.key(),
+        constraint = order.owner == user.key()
+    )]
+    pub order: Box<Account<'info, LimitOrder>>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(order.bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array: AccountLoader<'info, BinArray>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CancelLimitOrder<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        close = user,
+        constraint = order.pool == pool.key(),
+        constraint = order.owner == user.key()
+    )]
+    pub order: Box<Account<'info, LimitOrder>>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(order.bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array: AccountLoader<'info, BinArray>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct Swap<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, seeds = [b"oracle", pool.key().as_ref()], bump = oracle.bump)]
+    pub oracle: Box<Account<'info, Oracle>>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+
+    // Partner account in the input mint that takes the pool's referral share
+    // of the protocol fee
+    #[account(mut)]
+    pub referrer_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(address = pool.factory)]
+    pub factory: Box<Account<'info, pudl_factory::Factory>>,
+
+    // The swapper's PUDL stake, which earns its tier's fee discount
+    #[account(
+        seeds = [b"stake", user.key().as_ref()],
+        bump,
+        seeds::program = pudl_staking::ID,
+        constraint = stake_account.owner == user.key() @ ErrorCode::Unauthorized
+    )]
+    pub stake_account: Option<Box<Account<'info, pudl_staking::StakeAccount>>>,
+}
+
+// The pool must stay the first account of both flash loan instructions, since
+// flash_borrow matches its repay instruction on it
+#[derive(Accounts)]
+pub struct FlashBorrow<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+
+    /// CHECK: Instructions sysvar
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FlashRepay<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(pool.flash_loan_bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array: AccountLoader<'info, BinArray>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub user_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CollectProtocolFees<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"treasury"],
+        bump = treasury.bump,
+        seeds::program = pudl_treasury::ID
+    )]
+    pub treasury: Account<'info, pudl_treasury::Treasury>,
+
+    #[account(
+        mut,
+        constraint = base_fee_vault.mint == pool.base_mint,
+        constraint = base_fee_vault.owner == treasury.key()
+    )]
+    pub base_fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = quote_fee_vault.mint == pool.quote_mint,
+        constraint = quote_fee_vault.owner == treasury.key()
+    )]
+    pub quote_fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub treasury_program: Program<'info, pudl_treasury::program::PudlTreasury>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteSwap<'info> {
+    #[account(
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(address = pool.base_mint)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.factory)]
+    pub factory: Box<Account<'info, pudl_factory::Factory>>,
+
+    // Quotes at this stake's tier discount
+    pub stake_account: Option<Box<Account<'info, pudl_staking::StakeAccount>>>,
+}
+
+#[derive(Accounts)]
+pub struct PausePool<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump,
+        constraint = pool.factory == factory.key()
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(seeds = [b"factory"], bump = factory.bump, seeds::program = pudl_factory::ID)]
+    pub factory: Account<'info, pudl_factory::Factory>,
+
+    #[account(constraint = factory.can_pause(&authority.key()) @ ErrorCode::Unauthorized)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UnpausePool<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump,
+        constraint = pool.factory == factory.key()
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(seeds = [b"factory"], bump = factory.bump, seeds::program = pudl_factory::ID)]
+    pub factory: Account<'info, pudl_factory::Factory>,
+
+    #[account(constraint = factory.governance == governance.key() @ ErrorCode::Unauthorized)]
+    pub governance: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct EmergencyWithdraw<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump,
+        constraint = pool.factory == factory.key()
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(seeds = [b"factory"], bump = factory.bump, seeds::program = pudl_factory::ID)]
+    pub factory: Account<'info, pudl_factory::Factory>,
+
+    #[account(constraint = factory.governance == governance.key() @ ErrorCode::Unauthorized)]
+    pub governance: Signer<'info>,
+
+    #[account(mut, constraint = position.pool == pool.key())]
+    pub position: Box<Account<'info, Position>>,
+
+    // Funds go to whoever holds the position NFT
+    #[account(
+        constraint = position_token_account.mint == position.nft_mint,
+        constraint = position_token_account.amount == 1 @ ErrorCode::NotPositionHolder
+    )]
+    pub position_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        token::mint = base_mint,
+        token::authority = position_token_account.owner
+    )]
+    pub owner_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = quote_mint,
+        token::authority = position_token_account.owner
+    )]
+    pub owner_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(reward_index: u8)]
+pub struct InitializeReward<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump,
+        constraint = pool.factory == factory.key()
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(seeds = [b"factory"], bump = factory.bump, seeds::program = pudl_factory::ID)]
+    pub factory: Account<'info, pudl_factory::Factory>,
+
+    #[account(
+        init,
+        payer = authority,
+        token::mint = reward_mint,
+        token::authority = pool,
+        token::token_program = token_program,
+        seeds = [b"reward_vault", pool.key().as_ref(), &[reward_index]],
+        bump
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mint::token_program = token_program)]
+    pub reward_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        constraint = authority.key() == pool.creator || authority.key() == factory.governance
+            @ ErrorCode::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(reward_index: u8)]
+pub struct FundReward<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    // Holds the active bin, which earns everything emitted up to now
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(pool.active_bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array: AccountLoader<'info, BinArray>,
+
+    #[account(
+        mut,
+        token::mint = reward_mint,
+        seeds = [b"reward_vault", pool.key().as_ref(), &[reward_index]],
+        bump
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mint::token_program = token_program)]
+    pub reward_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub funder: Signer<'info>,
+
+    #[account(mut, token::mint = reward_mint)]
+    pub funder_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ManageReward<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump,
+        constraint = pool.factory == factory.key()
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(seeds = [b"factory"], bump = factory.bump, seeds::program = pudl_factory::ID)]
+    pub factory: Account<'info, pudl_factory::Factory>,
+
+    #[account(
+        constraint = authority.key() == pool.creator || authority.key() == factory.governance
+            @ ErrorCode::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetRewardDuration<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump,
+        constraint = pool.factory == factory.key()
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(seeds = [b"factory"], bump = factory.bump, seeds::program = pudl_factory::ID)]
+    pub factory: Account<'info, pudl_factory::Factory>,
+
+    #[account(
+        constraint = authority.key() == pool.creator || authority.key() == factory.governance
+            @ ErrorCode::Unauthorized
+    )]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(pool.active_bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array: AccountLoader<'info, BinArray>,
+}
+
+#[derive(Accounts)]
+#[instruction(reward_index: u8)]
+pub struct ClaimReward<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, constraint = position.pool == pool.key())]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        constraint = position_token_account.mint == position.nft_mint,
+        constraint = position_token_account.owner == user.key(),
+        constraint = position_token_account.amount == 1 @ ErrorCode::NotPositionHolder
+    )]
+    pub position_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(position.lower_bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array_lower: AccountLoader<'info, BinArray>,
+
+    #[account(
+        mut,
+        seeds = [b"bin_array", pool.key().as_ref(), &bin_array_index(position.upper_bin_id).to_le_bytes()],
+        bump
+    )]
+    pub bin_array_upper: AccountLoader<'info, BinArray>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut, token::mint = reward_mint)]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = reward_mint,
+        seeds = [b"reward_vault", pool.key().as_ref(), &[reward_index]],
+        bump
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mint::token_program = token_program)]
+    pub reward_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(reward_index: u8)]
+pub struct WithdrawUndistributedReward<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        token::mint = reward_mint,
+        seeds = [b"reward_vault", pool.key().as_ref(), &[reward_index]],
+        bump
+    )]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mint::token_program = token_program)]
+    pub reward_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub funder: Signer<'info>,
+
+    #[account(mut, token::mint = reward_mint)]
+    pub funder_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeAlphaVault<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump,
+        constraint = pool.creator == creator.key() @ ErrorCode::Unauthorized
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init,
+        payer = creator,
+        space = 8 + AlphaVault::LEN,
+        seeds = [b"alpha_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub alpha_vault: Account<'info, AlphaVault>,
+
+    #[account(
+        init,
+        payer = creator,
+        token::mint = quote_mint,
+        token::authority = alpha_vault,
+        token::token_program = quote_token_program,
+        seeds = [b"alpha_quote", alpha_vault.key().as_ref()],
+        bump
+    )]
+    pub alpha_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = creator,
+        token::mint = base_mint,
+        token::authority = alpha_vault,
+        token::token_program = base_token_program,
+        seeds = [b"alpha_base", alpha_vault.key().as_ref()],
+        bump
+    )]
+    pub alpha_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateAlphaEscrow<'info> {
+    #[account(seeds = [b"alpha_vault", alpha_vault.pool.as_ref()], bump = alpha_vault.bump)]
+    pub alpha_vault: Account<'info, AlphaVault>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = 8 + AlphaEscrow::LEN,
+        seeds = [b"alpha_escrow", alpha_vault.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub escrow: Account<'info, AlphaEscrow>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositAlphaVault<'info> {
+    #[account(address = alpha_vault.pool)]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, seeds = [b"alpha_vault", pool.key().as_ref()], bump = alpha_vault.bump)]
+    pub alpha_vault: Account<'info, AlphaVault>,
+
+    #[account(
+        mut,
+        seeds = [b"alpha_escrow", alpha_vault.key().as_ref(), owner.key().as_ref()],
+        bump = escrow.bump
+    )]
+    pub escrow: Account<'info, AlphaEscrow>,
+
+    pub owner: Signer<'info>,
 
     #[account(mut)]
-    pub quote_vault: Account<'info, TokenAccount>,
+    pub owner_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = alpha_vault.quote_account)]
+    pub alpha_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct FillAlphaVault<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, seeds = [b"oracle", pool.key().as_ref()], bump = oracle.bump)]
+    pub oracle: Box<Account<'info, Oracle>>,
+
+    #[account(mut, seeds = [b"alpha_vault", pool.key().as_ref()], bump = alpha_vault.bump)]
+    pub alpha_vault: Account<'info, AlphaVault>,
+
+    #[account(mut, address = alpha_vault.quote_account)]
+    pub alpha_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = alpha_vault.base_account)]
+    pub alpha_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimAlphaVault<'info> {
+    #[account(address = alpha_vault.pool)]
+    pub pool: Account<'info, Pool>,
+
+    #[account(seeds = [b"alpha_vault", pool.key().as_ref()], bump = alpha_vault.bump)]
+    pub alpha_vault: Account<'info, AlphaVault>,
+
+    #[account(
+        mut,
+        seeds = [b"alpha_escrow", alpha_vault.key().as_ref(), owner.key().as_ref()],
+        bump = escrow.bump
+    )]
+    pub escrow: Account<'info, AlphaEscrow>,
+
+    pub owner: Signer<'info>,
+
+    #[account(mut, token::mint = base_mint)]
+    pub owner_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = quote_mint)]
+    pub owner_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = alpha_vault.base_account)]
+    pub alpha_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = alpha_vault.quote_account)]
+    pub alpha_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SetPoolCreator<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump,
+        constraint = pool.creator == creator.key() @ ErrorCode::Unauthorized
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool_meta.bump,
+        seeds::program = pudl_factory::ID
+    )]
+    pub pool_meta: Account<'info, pudl_factory::PoolMeta>,
+
+    pub creator: Signer<'info>,
+
+    pub factory_program: Program<'info, pudl_factory::program::PudlFactory>,
+}
+
+#[derive(Accounts)]
+pub struct SetFeeParams<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump,
+        constraint = pool.factory == factory.key(),
+        constraint = pool.creator == creator.key() @ ErrorCode::Unauthorized
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(seeds = [b"factory"], bump = factory.bump, seeds::program = pudl_factory::ID)]
+    pub factory: Account<'info, pudl_factory::Factory>,
+
+    pub creator: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetReferralFee<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump,
+        constraint = pool.factory == factory.key()
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(seeds = [b"factory"], bump = factory.bump, seeds::program = pudl_factory::ID)]
+    pub factory: Account<'info, pudl_factory::Factory>,
+
+    #[account(constraint = factory.governance == governance.key() @ ErrorCode::Unauthorized)]
+    pub governance: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetCreatorFee<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump,
+        constraint = pool.factory == factory.key()
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(seeds = [b"factory"], bump = factory.bump, seeds::program = pudl_factory::ID)]
+    pub factory: Account<'info, pudl_factory::Factory>,
+
+    #[account(
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool_meta.bump,
+        seeds::program = pudl_factory::ID
+    )]
+    pub pool_meta: Account<'info, pudl_factory::PoolMeta>,
+
+    pub authority: Signer<'info>,
+}
+
+// Creator fees belong to the wallet that bonded the pool in the factory
+#[derive(Accounts)]
+pub struct ClaimCreatorFees<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool.bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
+        bump = pool_meta.bump,
+        seeds::program = pudl_factory::ID,
+        constraint = pool_meta.creator == creator.key() @ ErrorCode::Unauthorized
+    )]
+    pub pool_meta: Account<'info, pudl_factory::PoolMeta>,
+
+    pub creator: Signer<'info>,
+
+    #[account(mut, token::mint = base_mint)]
+    pub creator_base_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = quote_mint)]
+    pub creator_quote_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.base_vault)]
+    pub base_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.base_mint, mint::token_program = base_token_program)]
+    pub base_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.quote_mint, mint::token_program = quote_token_program)]
+    pub quote_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    pub base_token_program: Interface<'info, TokenInterface>,
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[account]
+#[derive(Default)]
+pub struct Pool {
+    pub factory: Pubkey,
+    pub creator: Pubkey,
+    pub base_mint: Pubkey,
+    pub quote_mint: Pubkey,
+    pub base_vault: Pubkey,
+    pub quote_vault: Pubkey,
+    pub base_fee_bps: u16,
+    pub bin_step: u16,
+    pub active_bin_id: i32,
+    pub protocol_fee_bps: u16,
+    pub total_volume: u64,
+    pub total_fees: u64,
+    pub status: PoolStatus,
+    pub dynamic_fee_enabled: bool,
+    pub fee_params: DynamicFeeParams,
+    pub volatility: VolatilityState,
+    pub fee_schedule: FeeSchedule,
+    pub fee_schedule_start: u64,
+    pub protocol_fee_base: u64,
+    pub protocol_fee_quote: u64,
+    pub flash_loan_base: u64,
+    pub flash_loan_quote: u64,
+    pub flash_loan_bin_id: i32,
+    pub total_liquidity_shares: u128,
+    pub rewards: [RewardInfo; NUM_REWARDS],
+    pub activation_type: ScheduleUnit,
+    pub activation_point: u64,
+    pub alpha_vault_pending: bool,
+    pub referral_fee_bps: u16,
+    pub creator_fee_bps: u16,
+    pub creator_fee_base: u64,
+    pub creator_fee_quote: u64,
+    pub reserve_base: u64,
+    pub reserve_quote: u64,
+    // Limit order funds in the vaults, resting or filled and not yet claimed
+    pub order_base: u64,
+    pub order_quote: u64,
+    // Sum of the bin reserves
+    pub liquidity_base: u64,
+    pub liquidity_quote: u64,
+    // LP fees credited through fee growth and not yet claimed
+    pub lp_fee_base: u64,
+    pub lp_fee_quote: u64,
+    pub bump: u8,
+}
+
+impl Pool {
+    pub const LEN: usize = 32 + 32 + 32 + 32 + 32 + 32 + 2 + 2 + 4 + 2 + 8 + 8 + 1 + 1
+        + DynamicFeeParams::LEN
+        + VolatilityState::LEN
+        + FeeSchedule::LEN
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 4
+        + 16
+        + RewardInfo::LEN * NUM_REWARDS
+        + 1
+        + 8
+        + 1
+        + 2
+        + 2
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 1;
+
+    pub fn is_activated(&self, clock: &Clock) -> bool {
+        self.activation_type.current(clock) >= self.activation_point
+    }
+
+    // Trading opens at activation, or once the alpha vault has bought in for
+    // pools that have one
+    pub fn require_tradable(&self, clock: &Clock) -> Result<()> {
+        require!(self.is_activated(clock), ErrorCode::PoolNotActivated);
+        require!(!self.alpha_vault_pending, ErrorCode::AlphaVaultPending);
+        Ok(())
+    }
+
+    // Before activation only the creator can put liquidity in
+    pub fn require_can_seed(&self, clock: &Clock, depositor: &Pubkey) -> Result<()> {
+        require!(
+            self.is_activated(clock) || *depositor == self.creator,
+            ErrorCode::PoolNotActivated
+        );
+        Ok(())
+    }
+
+    // Everything that moves vault funds or the active bin waits until an
+    // outstanding flash loan is repaid
+    pub fn require_no_flash_loan(&self) -> Result<()> {
+        require!(
+            self.flash_loan_base == 0 && self.flash_loan_quote == 0,
+            ErrorCode::FlashLoanActive
+        );
+        Ok(())
+    }
+
+    // Reserves track every token the pool owes out of a vault: bin liquidity,
+    // limit orders, and LP, protocol and creator fees not yet claimed. They
+    // move with each transfer in or out of the vaults.
+    pub fn add_reserves(&mut self, base: u64, quote: u64) -> Result<()> {
+        self.reserve_base = self.reserve_base.checked_add(base).ok_or(ErrorCode::MathOverflow)?;
+        self.reserve_quote = self.reserve_quote.checked_add(quote).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    pub fn sub_reserves(&mut self, base: u64, quote: u64) -> Result<()> {
+        self.reserve_base = self
+            .reserve_base
+            .checked_sub(base)
+            .ok_or(ErrorCode::ReserveInvariantViolated)?;
+        self.reserve_quote = self
+            .reserve_quote
+            .checked_sub(quote)
+            .ok_or(ErrorCode::ReserveInvariantViolated)?;
+        Ok(())
+    }
+
+    // Run once an instruction's transfers are done. The reserve has to equal
+    // what the pool owes, which is bin liquidity, unclaimed LP fees, limit
+    // orders and protocol and creator fees. Each vault has to cover its
+    // reserve, less whatever is out on a flash loan. Only the vault balance
+    // above the reserve, which was sent there directly, can be skimmed.
+    pub fn check_reserves(
+        &self,
+        base_vault: &mut InterfaceAccount<TokenAccount>,
+        quote_vault: &mut InterfaceAccount<TokenAccount>,
+    ) -> Result<()> {
+        self.check_balances()?;
+        self.check_vault(base_vault)?;
+        self.check_vault(quote_vault)
+    }
+
+    pub fn check_reserve(&self, vault: &mut InterfaceAccount<TokenAccount>) -> Result<()> {
+        self.check_balances()?;
+        self.check_vault(vault)
+    }
+
+    // Only that each reserve equals what the pool owes out of it
+    pub fn check_balances(&self) -> Result<()> {
+        let owed_base = [self.liquidity_base, self.lp_fee_base, self.order_base, self.protocol_fee_base, self.creator_fee_base];
+        let owed_quote =
+            [self.liquidity_quote, self.lp_fee_quote, self.order_quote, self.protocol_fee_quote, self.creator_fee_quote];
+        require!(
+            self.reserve_base as u128 == owed_base.iter().map(|amount| *amount as u128).sum::<u128>()
+                && self.reserve_quote as u128 == owed_quote.iter().map(|amount| *amount as u128).sum::<u128>(),
+            ErrorCode::ReserveInvariantViolated
+        );
+        Ok(())
+    }
+
+    // Vault balances above the reserves, sent in directly rather than through
+    // the pool
+    pub fn skimmable(&self, base_vault_amount: u64, quote_vault_amount: u64) -> (u64, u64) {
+        (
+            base_vault_amount.saturating_sub(self.reserve_base),
+            quote_vault_amount.saturating_sub(self.reserve_quote),
+        )
+    }
+
+    // Only that the vault covers the reserve
+    pub fn check_vault(&self, vault: &mut InterfaceAccount<TokenAccount>) -> Result<()> {
+        vault.reload()?;
+        let (reserve, lent) = if vault.key() == self.base_vault {
+            (self.reserve_base, self.flash_loan_base)
+        } else {
+            (self.reserve_quote, self.flash_loan_quote)
+        };
+        require!(
+            vault.amount as u128 + lent as u128 >= reserve as u128,
+            ErrorCode::ReserveInvariantViolated
+        );
+        Ok(())
+    }
+
+    // Base fee for the current slot or time, following the launch schedule
+    // until it runs out
+    pub fn scheduled_fee_bps(&self, slot: u64, timestamp: i64) -> Result<u16> {
+        let schedule = &self.fee_schedule;
+        let current = match schedule.unit {
+            ScheduleUnit::Slot => slot,
+            ScheduleUnit::Timestamp => timestamp.max(0) as u64,
+        };
+        let period = current
+            .saturating_sub(self.fee_schedule_start)
+            .checked_div(schedule.period_frequency)
+            .unwrap_or(u64::MAX);
+        if period >= schedule.number_of_periods as u64 {
+            return Ok(self.base_fee_bps);
+        }
+
+        let fee_bps = match schedule.mode {
+            FeeScheduleMode::None => return Ok(self.base_fee_bps),
+            FeeScheduleMode::Linear => (schedule.cliff_fee_bps as u64)
+                .saturating_sub(schedule.reduction_factor as u64 * period),
+            FeeScheduleMode::Exponential => {
+                let retained = ONE - ((schedule.reduction_factor as u128) << SCALE_OFFSET) / BASIS_POINT_MAX;
+                let factor = pow(retained, period as i32).ok_or(ErrorCode::MathOverflow)?;
+                to_u64(mul_shr(schedule.cliff_fee_bps as u128, factor, Rounding::Down))?
+            }
+        };
+
+        Ok(fee_bps.max(self.base_fee_bps as u64) as u16)
+    }
+
+    // Emergency payout for `shares`: their cut of each vault by liquidity
+    // shares alone, after protocol and creator fees and limit order funds.
+    // Never reads the bins, so a corrupted bin cannot skew it.
+    pub fn emergency_withdraw_shares(
+        &mut self,
+        shares: u128,
+        base_vault_amount: u64,
+        quote_vault_amount: u64,
+    ) -> Result<(u64, u64)> {
+        require!(shares > 0, ErrorCode::ZeroLiquidity);
+        let total = self.total_liquidity_shares.max(shares);
+
+        let base_available = base_vault_amount
+            .min(self.reserve_base)
+            .saturating_sub(self.protocol_fee_base + self.creator_fee_base + self.order_base);
+        let quote_available = quote_vault_amount
+            .min(self.reserve_quote)
+            .saturating_sub(self.protocol_fee_quote + self.creator_fee_quote + self.order_quote);
+        let base_amount = to_u64(mul_div(base_available as u128, shares, total, Rounding::Down))?;
+        let quote_amount = to_u64(mul_div(quote_available as u128, shares, total, Rounding::Down))?;
+
+        // The payout leaves the LP balances, liquidity first and then fees
+        let base_liquidity = base_amount.min(self.liquidity_base);
+        self.liquidity_base -= base_liquidity;
+        self.lp_fee_base = self.lp_fee_base.saturating_sub(base_amount - base_liquidity);
+        let quote_liquidity = quote_amount.min(self.liquidity_quote);
+        self.liquidity_quote -= quote_liquidity;
+        self.lp_fee_quote = self.lp_fee_quote.saturating_sub(quote_amount - quote_liquidity);
+
+        self.total_liquidity_shares = total - shares;
+        self.sub_reserves(base_amount, quote_amount)?;
+        Ok((base_amount, quote_amount))
+    }
+
+    // A flash loan pays what a swap would right now: the launch schedule's
+    // base fee plus the variable fee, rounded up
+    pub fn flash_loan_fee(&self, amount: u64, slot: u64, now: i64) -> Result<u64> {
+        let fee_bps = self.total_fee_bps(self.scheduled_fee_bps(slot, now)?) as u128;
+        to_u64(Some((amount as u128 * fee_bps).div_ceil(BASIS_POINT_MAX)))
+    }
+
+    pub fn reward_mut(&mut self, reward_index: u8) -> Result<&mut RewardInfo> {
+        let reward = self
+            .rewards
+            .get_mut(reward_index as usize)
+            .ok_or(ErrorCode::InvalidRewardIndex)?;
+        require!(reward.initialized(), ErrorCode::RewardNotInitialized);
+        Ok(reward)
+    }
+
+    // Credits what every stream emitted since its last update to the active
+    // bin's shareholders, or sets it aside for the funder when the bin is
+    // empty. Must run before the active bin or its liquidity changes. When the
+    // active bin's array isn't loaded neither can change, so it is skipped.
+    pub fn update_rewards<S: BinStore>(&mut self, bins: &mut S, now: i64) -> Result<()> {
+        let Some(bin) = bins.bin_mut(self.active_bin_id) else {
+            return Ok(());
+        };
+
+        for (i, reward) in self.rewards.iter_mut().enumerate() {
+            if !reward.initialized() {
+                continue;
+            }
+
+            let end = now.min(reward.reward_duration_end);
+            if end > reward.last_update_time {
+                let emitted = reward
+                    .reward_rate_x64
+                    .checked_mul((end - reward.last_update_time) as u128)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                match emitted.checked_div(bin.liquidity_supply) {
+                    Some(growth) => {
+                        bin.reward_growth_x64[i] = bin.reward_growth_x64[i].wrapping_add(growth);
+                    }
+                    None => {
+                        reward.undistributed_x64 = reward
+                            .undistributed_x64
+                            .checked_add(emitted)
+                            .ok_or(ErrorCode::MathOverflow)?;
+                    }
+                }
+            }
+            reward.last_update_time = reward.last_update_time.max(now);
+        }
+
+        Ok(())
+    }
+
+    // Decays the volatility reference depending on how long the pool has
+    // been idle. Runs once per swap, before any bin is crossed.
+    pub fn update_references(&mut self, now: i64) {
+        let elapsed = now.saturating_sub(self.volatility.last_update_timestamp);
+
+        if elapsed >= self.fee_params.filter_period as i64 {
+            self.volatility.index_reference = self.active_bin_id;
+            self.volatility.volatility_reference = if elapsed < self.fee_params.decay_period as i64 {
+                (self.volatility.volatility_accumulator as u64
+                    * self.fee_params.reduction_factor as u64
+                    / BASIS_POINT_MAX as u64) as u32
+            } else {
+                0
+            };
+        }
+    }
+
+    // Grows the accumulator with the number of bins moved since the reference
+    pub fn update_volatility_accumulator(&mut self) {
+        let delta = (self.volatility.index_reference as i64 - self.active_bin_id as i64).unsigned_abs();
+        let accumulator = self.volatility.volatility_reference as u64 + delta * BASIS_POINT_MAX as u64;
+        self.volatility.volatility_accumulator =
+            accumulator.min(self.fee_params.max_volatility_accumulator as u64) as u32;
+    }
+
+    // (volatility_accumulator * bin_step)^2 * variable_fee_control, in bps
+    pub fn variable_fee_bps(&self) -> u128 {
+        let square = self.volatility.volatility_accumulator as u128 * self.bin_step as u128;
+        (square * square * self.fee_params.variable_fee_control as u128).div_ceil(VARIABLE_FEE_PRECISION)
+    }
+
+    pub fn total_fee_bps(&self, base_fee_bps: u16) -> u16 {
+        if !self.dynamic_fee_enabled {
+            return base_fee_bps;
+        }
+
+        let total = base_fee_bps as u128 + self.variable_fee_bps();
+        let max_fee_bps = self.fee_params.max_fee_bps.max(base_fee_bps);
+        total.min(max_fee_bps as u128) as u16
+    }
+}
+
+// Scales (accumulator * bin_step)^2 * control down to bps
+pub const VARIABLE_FEE_PRECISION: u128 = 10_000_000_000_000_000;
+
+pub const NUM_REWARDS: usize = 2;
+
+// A farming stream paying `reward_rate_x64` tokens per second (Q64.64) until
+// `reward_duration_end`, only to liquidity in the active bin
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct RewardInfo {
+    pub mint: Pubkey,
+    pub vault: Pubkey,
+    pub funder: Pubkey,
+    pub reward_duration: u64,
+    pub reward_duration_end: i64,
+    pub reward_rate_x64: u128,
+    pub last_update_time: i64,
+    pub undistributed_x64: u128,
+}
+
+impl RewardInfo {
+    pub const LEN: usize = 32 + 32 + 32 + 8 + 8 + 16 + 8 + 16;
+
+    pub fn initialized(&self) -> bool {
+        self.mint != Pubkey::default()
+    }
+
+    // Spreads whatever the stream has not emitted yet plus `amount` over a
+    // full duration starting now. Rewards must be updated up to `now` first.
+    pub fn restart(&mut self, now: i64, amount: u64) -> Result<()> {
+        let unemitted = if now < self.reward_duration_end {
+            self.reward_rate_x64
+                .checked_mul((self.reward_duration_end - now) as u128)
+                .ok_or(ErrorCode::MathOverflow)?
+        } else {
+            0
+        };
+        let total = unemitted
+            .checked_add((amount as u128) << SCALE_OFFSET)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        self.reward_rate_x64 = total / self.reward_duration as u128;
+        self.reward_duration_end = now + self.reward_duration as i64;
+        self.last_update_time = now;
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoolStatus {
+    #[default]
+    Active,
+    SwapsDisabled,
+    DepositsDisabled,
+    // Only withdrawals, fee claims and limit order exits
+    WithdrawOnly,
+    // Normal withdrawals stop and governance pays positions out of the vaults.
+    // Limit orders can always be claimed and cancelled.
+    Emergency,
+}
+
+impl PoolStatus {
+    pub fn can_swap(&self) -> bool {
+        matches!(self, PoolStatus::Active | PoolStatus::DepositsDisabled)
+    }
+
+    pub fn can_deposit(&self) -> bool {
+        matches!(self, PoolStatus::Active | PoolStatus::SwapsDisabled)
+    }
+
+    pub fn can_withdraw(&self) -> bool {
+        *self != PoolStatus::Emergency
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct DynamicFeeParams {
+    // Seconds after a swap during which the references are kept as they are
+    pub filter_period: u16,
+    // Seconds after which the volatility reference resets to zero
+    pub decay_period: u16,
+    // Share of the accumulator carried into the reference, in bps
+    pub reduction_factor: u16,
+    pub variable_fee_control: u32,
+    pub max_volatility_accumulator: u32,
+    pub max_fee_bps: u16,
+}
+
+impl DynamicFeeParams {
+    pub const LEN: usize = 2 + 2 + 2 + 4 + 4 + 2;
+
+    // The total fee, variable part included, stays within the factory's
+    // maximum base fee
+    pub fn validate(&self, base_fee_bps: u16, factory_max_fee_bps: u16) -> Result<()> {
+        require!(
+            self.max_fee_bps >= base_fee_bps
+                && self.max_fee_bps <= factory_max_fee_bps
+                && (self.max_fee_bps as u128) < BASIS_POINT_MAX,
+            ErrorCode::InvalidFeeParams
+        );
+        require!(
+            self.filter_period < self.decay_period
+                && self.reduction_factor as u128 <= BASIS_POINT_MAX,
+            ErrorCode::InvalidFeeParams
+        );
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct VolatilityState {
+    pub volatility_accumulator: u32,
+    pub volatility_reference: u32,
+    pub index_reference: i32,
+    pub last_update_timestamp: i64,
+}
+
+impl VolatilityState {
+    pub const LEN: usize = 4 + 4 + 4 + 8;
+}
+
+#[account]
+pub struct Position {
+    pub nft_mint: Pubkey,
+    pub pool: Pubkey,
+    pub lower_bin_id: i32,
+    pub upper_bin_id: i32,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+    pub fee_debt_base: [u128; MAX_BIN_PER_POSITION],
+    pub fee_debt_quote: [u128; MAX_BIN_PER_POSITION],
+    pub liquidity_shares: [u128; MAX_BIN_PER_POSITION],
+    pub fees_owed_base: u64,
+    pub fees_owed_quote: u64,
+    pub reward_debt_x64: [[u128; NUM_REWARDS]; MAX_BIN_PER_POSITION],
+    pub rewards_owed: [u64; NUM_REWARDS],
+}
+
+impl Position {
+    pub const LEN: usize = 32 + 32 + 4 + 4 + 8 + 8 + 16 * MAX_BIN_PER_POSITION * 3 + 8 + 8
+        + 16 * NUM_REWARDS * MAX_BIN_PER_POSITION
+        + 8 * NUM_REWARDS;
+
+    // Credits fees and rewards earned since the last checkpoint in every bin
+    // of the range and moves the checkpoints up to the bins' current growth.
+    pub fn update_fees(&mut self, bins: &mut BinArrays<RefMut<BinArray>>) -> Result<()> {
+        for bin_id in self.lower_bin_id..=self.upper_bin_id {
+            let i = (bin_id - self.lower_bin_id) as usize;
+            let bin = bins.bin(bin_id)?;
+            let shares = self.liquidity_shares[i];
+
+            if shares > 0 {
+                let base_growth = bin.fee_growth_base_x64.wrapping_sub(self.fee_debt_base[i]);
+                let quote_growth = bin.fee_growth_quote_x64.wrapping_sub(self.fee_debt_quote[i]);
+                let base_fees = to_u64(mul_shr(shares, base_growth, Rounding::Down))?;
+                let quote_fees = to_u64(mul_shr(shares, quote_growth, Rounding::Down))?;
+
+                self.fees_owed_base = self.fees_owed_base.checked_add(base_fees).ok_or(ErrorCode::MathOverflow)?;
+                self.fees_owed_quote = self.fees_owed_quote.checked_add(quote_fees).ok_or(ErrorCode::MathOverflow)?;
+            }
+
+            self.fee_debt_base[i] = bin.fee_growth_base_x64;
+            self.fee_debt_quote[i] = bin.fee_growth_quote_x64;
+
+            for (r, growth) in bin.reward_growth_x64.iter().enumerate() {
+                if shares > 0 {
+                    let rewards = to_u64(mul_shr(
+                        shares,
+                        growth.wrapping_sub(self.reward_debt_x64[i][r]),
+                        Rounding::Down,
+                    ))?;
+                    self.rewards_owed[r] = self.rewards_owed[r].checked_add(rewards).ok_or(ErrorCode::MathOverflow)?;
+                }
+                self.reward_debt_x64[i][r] = *growth;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.liquidity_shares.iter().all(|shares| *shares == 0)
+    }
+
+    pub fn shares_mut(&mut self, bin_id: i32) -> Result<&mut u128> {
+        require!(
+            bin_id >= self.lower_bin_id && bin_id <= self.upper_bin_id,
+            ErrorCode::InvalidBinRange
+        );
+        Ok(&mut self.liquidity_shares[(bin_id - self.lower_bin_id) as usize])
+    }
+}
+
+// A position spans at most two bin arrays, which only holds while it is no
+// wider than an array plus one bin
+pub const MAX_BIN_PER_POSITION: usize = 52;
+const _: () = assert!(MAX_BIN_PER_POSITION <= MAX_BIN_PER_ARRAY + 1);
+
+pub const POSITION_NFT_NAME: &str = "PUDL LP Position";
+pub const POSITION_NFT_SYMBOL: &str = "PUDL-LP";
+pub const POSITION_NFT_URI: &str = "";
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum LiquidityShape {
+    // Same weight in every bin
+    Spot,
+    // Weight falls off with distance from the active bin
+    Curve,
+    // Weight grows with distance from the active bin
+    BidAsk,
+    // One weight per bin from lower_bin_id to upper_bin_id
+    Custom { weights: Vec<u16> },
+}
+
+// Splits base across bins at or above the active bin and quote across bins at
+// or below it, returning (bin_id, base, quote) for every bin in the range.
+pub fn distribute_liquidity(
+    shape: &LiquidityShape,
+    lower_bin_id: i32,
+    upper_bin_id: i32,
+    active_bin_id: i32,
+    base_amount: u64,
+    quote_amount: u64,
+) -> Result<Vec<(i32, u64, u64)>> {
+    let weights = liquidity_weights(shape, lower_bin_id, upper_bin_id, active_bin_id)?;
+    let bins = (lower_bin_id..=upper_bin_id).zip(weights);
+    let (base_weight, quote_weight) = side_weights(bins.clone(), active_bin_id);
+    require!(base_amount == 0 || base_weight > 0, ErrorCode::InvalidDistribution);
+    require!(quote_amount == 0 || quote_weight > 0, ErrorCode::InvalidDistribution);
+
+    Ok(bins
+        .map(|(bin_id, weight)| {
+            let bin_base = if bin_id >= active_bin_id {
+                (base_amount as u128 * weight as u128 / base_weight as u128) as u64
+            } else {
+                0
+            };
+            let bin_quote = if bin_id <= active_bin_id {
+                (quote_amount as u128 * weight as u128 / quote_weight as u128) as u64
+            } else {
+                0
+            };
+            (bin_id, bin_base, bin_quote)
+        })
+        .collect())
+}
+
+pub fn liquidity_weights(
+    shape: &LiquidityShape,
+    lower_bin_id: i32,
+    upper_bin_id: i32,
+    active_bin_id: i32,
+) -> Result<Vec<u64>> {
+    let width = (upper_bin_id - lower_bin_id + 1) as usize;
+    let max_distance = (active_bin_id - lower_bin_id)
+        .abs()
+        .max((upper_bin_id - active_bin_id).abs()) as u64;
+
+    Ok(match shape {
+        LiquidityShape::Spot => vec![1; width],
+        LiquidityShape::Curve => (lower_bin_id..=upper_bin_id)
+            .map(|bin_id| max_distance + 1 - (bin_id - active_bin_id).unsigned_abs() as u64)
+            .collect(),
+        LiquidityShape::BidAsk => (lower_bin_id..=upper_bin_id)
+            .map(|bin_id| (bin_id - active_bin_id).unsigned_abs() as u64 + 1)
+            .collect(),
+        LiquidityShape::Custom { weights } => {
+            require!(weights.len() == width, ErrorCode::InvalidDistribution);
+            weights.iter().map(|weight| *weight as u64).collect()
+        }
+    })
+}
+
+// Total weight taking base (bins at or above the active bin) and quote (bins
+// at or below it)
+pub fn side_weights(bins: impl Iterator<Item = (i32, u64)> + Clone, active_bin_id: i32) -> (u64, u64) {
+    let base_weight = bins.clone().filter(|(bin_id, _)| *bin_id >= active_bin_id).map(|(_, w)| w).sum();
+    let quote_weight = bins.filter(|(bin_id, _)| *bin_id <= active_bin_id).map(|(_, w)| w).sum();
+    (base_weight, quote_weight)
+}
+
+// A resting limit order in a single bin. Orders on the same side of a bin
+// fill pro rata: the side's factor shrinks with every fill, so an order's
+// unfilled amount is amount * factor_now / factor_at_placement. A side that
+// is drained starts a new generation, and orders from an older generation
+// are fully filled.
+#[account]
+pub struct LimitOrder {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub bin_id: i32,
+    pub is_ask: bool,
+    pub amount: u64,
+    pub generation: u64,
+    pub factor_x64: u128,
+    pub converted_claimed: u64,
+}
+
+impl LimitOrder {
+    pub const LEN: usize = 32 + 32 + 4 + 1 + 8 + 8 + 16 + 8;
+
+    pub fn remaining(&self, side: &OrderSide) -> Result<u64> {
+        if self.generation != side.generation || side.factor_x64 == 0 {
+            return Ok(0);
+        }
+
+        let remaining = to_u64(mul_div(
+            self.amount as u128,
+            side.factor_x64,
+            self.factor_x64,
+            Rounding::Down,
+        ))?;
+        Ok(remaining.min(self.amount).min(side.amount))
+    }
+
+    // Takes whatever the filled part of the order has been converted into
+    // since the last claim out of the side's filled bucket
+    pub fn take_converted(&mut self, side: &mut OrderSide, price: u128) -> Result<u64> {
+        let filled = self.amount - self.remaining(side)?;
+        let converted = get_amount_out(filled, price, self.is_ask)?;
+        let owed = converted.saturating_sub(self.converted_claimed).min(side.filled);
+
+        side.filled -= owed;
+        self.converted_claimed += owed;
+        Ok(owed)
+    }
+}
+
+// Pre-launch vault: whitelisted wallets pool quote before activation, which
+// is swapped into base in a single fill and vested back to them pro rata
+#[account]
+pub struct AlphaVault {
+    pub pool: Pubkey,
+    pub merkle_root: [u8; 32],
+    pub quote_account: Pubkey,
+    pub base_account: Pubkey,
+    pub max_buying_cap: u64,
+    // Highest bin the fill may buy in
+    pub max_price_bin_id: i32,
+    pub total_deposited: u64,
+    pub quote_spent: u64,
+    pub base_bought: u64,
+    // In the pool's activation unit
+    pub vesting_start: u64,
+    pub vesting_duration: u64,
+    pub filled: bool,
+    pub bump: u8,
+}
+
+impl AlphaVault {
+    pub const LEN: usize = 32 + 32 + 32 + 32 + 8 + 4 + 8 + 8 + 8 + 8 + 8 + 1 + 1;
+
+    pub fn vested_base(&self, escrow: &AlphaEscrow, now_point: u64) -> Result<u64> {
+        let share = to_u64(mul_div(
+            self.base_bought as u128,
+            escrow.deposited as u128,
+            self.total_deposited.max(1) as u128,
+            Rounding::Down,
+        ))?;
+        let elapsed = now_point.saturating_sub(self.vesting_start);
+        if elapsed >= self.vesting_duration {
+            return Ok(share);
+        }
+        to_u64(mul_div(share as u128, elapsed as u128, self.vesting_duration as u128, Rounding::Down))
+    }
+
+    pub fn quote_refund(&self, escrow: &AlphaEscrow) -> Result<u64> {
+        to_u64(mul_div(
+            (self.total_deposited - self.quote_spent) as u128,
+            escrow.deposited as u128,
+            self.total_deposited.max(1) as u128,
+            Rounding::Down,
+        ))
+    }
+}
+
+#[account]
+pub struct AlphaEscrow {
+    pub alpha_vault: Pubkey,
+    pub owner: Pubkey,
+    pub max_cap: u64,
+    pub deposited: u64,
+    pub claimed_base: u64,
+    pub refunded: bool,
+    pub bump: u8,
+}
+
+impl AlphaEscrow {
+    pub const LEN: usize = 32 + 32 + 8 + 8 + 8 + 1 + 1;
+}
+
+// Whitelist leaves are sha256(0x00 || owner || max_cap as le bytes) and
+// inner nodes sha256(0x01 || lower child || higher child)
+pub fn verify_whitelist(root: &[u8; 32], owner: &Pubkey, max_cap: u64, proof: &[[u8; 32]]) -> bool {
+    let leaf = hashv(&[&[0], owner.as_ref(), &max_cap.to_le_bytes()]).to_bytes();
+    let computed = proof.iter().fold(leaf, |node, sibling| {
+        let (a, b) = if node <= *sibling { (node, *sibling) } else { (*sibling, node) };
+        hashv(&[&[1], &a, &b]).to_bytes()
+    });
+    computed == *root
+}
+
+// Bin arrays are created through a CPI to the system program, which caps
+// the account at 10KiB
+pub const MAX_BIN_PER_ARRAY: usize = 52;
+const _: () = assert!(8 + BinArray::LEN <= 10240);
+
+#[account(zero_copy)]
+pub struct BinArray {
+    pub index: i64,
+    pub _padding: [u8; 8],
+    pub pool: Pubkey,
+    pub bins: [Bin; MAX_BIN_PER_ARRAY],
+}
+
+impl BinArray {
+    pub const LEN: usize = 8 + 8 + 32 + Bin::LEN * MAX_BIN_PER_ARRAY;
+
+    pub fn contains(&self, bin_id: i32) -> bool {
+        bin_array_index(bin_id) == self.index
+    }
+
+    pub fn bin_mut(&mut self, bin_id: i32) -> Result<&mut Bin> {
+        require!(self.contains(bin_id), ErrorCode::InvalidBinArray);
+        Ok(&mut self.bins[bin_id.rem_euclid(MAX_BIN_PER_ARRAY as i32) as usize])
+    }
+}
+
+pub fn bin_array_index(bin_id: i32) -> i64 {
+    bin_id.div_euclid(MAX_BIN_PER_ARRAY as i32) as i64
+}
+
+pub const DEFAULT_ORACLE_LENGTH: usize = 24;
+// Anyone can grow the oracle up to this length; past it only the creator can
+pub const PERMISSIONLESS_ORACLE_LENGTH: usize = 64;
+// Every swap deserializes the whole oracle onto the 32KiB heap, so the length
+// is capped outright. Growing from the default to the cap in one call also
+// stays under the 10KiB realloc limit.
+pub const MAX_ORACLE_LENGTH: usize = 256;
+// Swaps inside this window fold into the latest observation instead of
+// taking a new slot
+pub const ORACLE_SAMPLE_LIFETIME: i64 = 120;
+
+// Ring buffer of time-weighted cumulative active bin ids. Empty slots have
+// created_at == 0, and walking back from idx stops at the first one.
+#[account]
+pub struct Oracle {
+    pub pool: Pubkey,
+    pub idx: u16,
+    pub observations: Vec<Observation>,
+    pub bump: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct Observation {
+    pub cumulative_active_bin_id: i128,
+    pub created_at: i64,
+    pub last_updated_at: i64,
+}
+
+impl Observation {
+    pub const LEN: usize = 16 + 8 + 8;
+
+    pub fn is_empty(&self) -> bool {
+        self.created_at == 0
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct TwapQuote {
+    pub average_bin_id: i32,
+    pub price_x64: u128,
+    pub window: u64,
+}
+
+impl Oracle {
+    pub fn space(length: usize) -> usize {
+        8 + 32 + 2 + 4 + Observation::LEN * length + 1
+    }
+
+    pub fn update(&mut self, now: i64, active_bin_id: i32) -> Result<()> {
+        let len = self.observations.len();
+        let latest = &mut self.observations[self.idx as usize];
+        if latest.is_empty() {
+            *latest = Observation {
+                cumulative_active_bin_id: 0,
+                created_at: now,
+                last_updated_at: now,
+            };
+            return Ok(());
+        }
+
+        let elapsed = now.saturating_sub(latest.last_updated_at);
+        let cumulative_active_bin_id = latest
+            .cumulative_active_bin_id
+            .checked_add(active_bin_id as i128 * elapsed as i128)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        if now - latest.created_at >= ORACLE_SAMPLE_LIFETIME {
+            self.idx = ((self.idx as usize + 1) % len) as u16;
+            self.observations[self.idx as usize] = Observation {
+                cumulative_active_bin_id,
+                created_at: now,
+                last_updated_at: now,
+            };
+        } else {
+            latest.cumulative_active_bin_id = cumulative_active_bin_id;
+            latest.last_updated_at = now;
+        }
+
+        Ok(())
+    }
+
+    // New slots go right after the latest observation so that they are the
+    // next ones written and the chronological order of the ring is kept
+    pub fn grow(&mut self, length_to_add: usize) {
+        let at = self.idx as usize + 1;
+        self.observations
+            .splice(at..at, std::iter::repeat_n(Observation::default(), length_to_add));
+    }
+
+    // Average active bin id over the last `window` seconds, or over all the
+    // history there is if that is shorter. Also returns the window covered.
+    pub fn twap(&self, now: i64, active_bin_id: i32, window: u64) -> Result<(i32, u64)> {
+        require!(window > 0, ErrorCode::InvalidOracleWindow);
+
+        let len = self.observations.len();
+        let latest = self.observations[self.idx as usize];
+        require!(!latest.is_empty(), ErrorCode::OracleNotReady);
+
+        let elapsed = now.saturating_sub(latest.last_updated_at);
+        let cumulative_now = latest.cumulative_active_bin_id + active_bin_id as i128 * elapsed as i128;
+        let target = now.saturating_sub(window.min(i64::MAX as u64) as i64);
+
+        // Walk back to the newest observation at or before the target and
+        // interpolate between it and the one after
+        let mut newer = Observation {
+            cumulative_active_bin_id: cumulative_now,
+            created_at: now,
+            last_updated_at: now,
+        };
+        let mut start = newer;
+        for i in 0..len {
+            let older = self.observations[(self.idx as usize + len - i) % len];
+            if older.is_empty() {
+                break;
+            }
+
+            if older.last_updated_at <= target {
+                let span = (newer.last_updated_at - older.last_updated_at) as i128;
+                let cumulative = if span == 0 {
+                    older.cumulative_active_bin_id
+                } else {
+                    older.cumulative_active_bin_id
+                        + (newer.cumulative_active_bin_id - older.cumulative_active_bin_id)
+                            * (target - older.last_updated_at) as i128
+                            / span
+                };
+                start = Observation {
+                    cumulative_active_bin_id: cumulative,
+                    created_at: target,
+                    last_updated_at: target,
+                };
+                break;
+            }
+
+            start = older;
+            newer = older;
+        }
+
+        let covered = now - start.last_updated_at;
+        require!(covered > 0, ErrorCode::OracleNotReady);
+
+        let average = (cumulative_now - start.cumulative_active_bin_id).div_euclid(covered as i128);
+        Ok((average as i32, covered as u64))
+    }
+}
+
+#[event]
+pub struct PositionOpened {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub position: Pubkey,
+    pub lower_bin_id: i32,
+    pub upper_bin_id: i32,
+}
+
+#[event]
+pub struct PositionClosed {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub position: Pubkey,
+}
+
+#[event]
+pub struct LiquidityAdded {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub position: Pubkey,
+    pub lower_bin_id: i32,
+    pub upper_bin_id: i32,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+}
+
+#[event]
+pub struct LiquidityRemoved {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub position: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+}
+
+#[event]
+pub struct PositionRebalanced {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub position: Pubkey,
+    pub lower_bin_id: i32,
+    pub upper_bin_id: i32,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+    pub swap_amount_in: u64,
+    pub swap_amount_out: u64,
+}
+
+#[event]
+pub struct FeesClaimed {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub position: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+}
+
+#[event]
+pub struct RewardInitialized {
+    pub pool: Pubkey,
+    pub reward_index: u8,
+    pub mint: Pubkey,
+    pub funder: Pubkey,
+    pub reward_duration: u64,
+}
+
+#[event]
+pub struct RewardFunded {
+    pub pool: Pubkey,
+    pub reward_index: u8,
+    pub funder: Pubkey,
+    pub amount: u64,
+    pub reward_rate_x64: u128,
+    pub reward_duration_end: i64,
+}
+
+#[event]
+pub struct RewardFunderUpdated {
+    pub pool: Pubkey,
+    pub reward_index: u8,
+    pub old: Pubkey,
+    pub new: Pubkey,
+}
+
+#[event]
+pub struct RewardDurationUpdated {
+    pub pool: Pubkey,
+    pub reward_index: u8,
+    pub reward_duration: u64,
+    pub reward_rate_x64: u128,
+    pub reward_duration_end: i64,
+}
+
+#[event]
+pub struct RewardClaimed {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub position: Pubkey,
+    pub reward_index: u8,
+    pub amount: u64,
+}
+
+#[event]
+pub struct UndistributedRewardWithdrawn {
+    pub pool: Pubkey,
+    pub reward_index: u8,
+    pub funder: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct AlphaVaultInitialized {
+    pub pool: Pubkey,
+    pub alpha_vault: Pubkey,
+    pub max_buying_cap: u64,
+    pub max_price_bin_id: i32,
+    pub vesting_duration: u64,
+}
+
+#[event]
+pub struct AlphaVaultDeposited {
+    pub alpha_vault: Pubkey,
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub total_deposited: u64,
+}
+
+#[event]
+pub struct AlphaVaultFilled {
+    pub pool: Pubkey,
+    pub alpha_vault: Pubkey,
+    pub quote_spent: u64,
+    pub base_bought: u64,
+}
+
+#[event]
+pub struct AlphaVaultClaimed {
+    pub alpha_vault: Pubkey,
+    pub owner: Pubkey,
+    pub base_amount: u64,
+    pub quote_refund: u64,
+}
+
+#[event]
+pub struct ProtocolFeesCollected {
+    pub pool: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+}
+
+#[event]
+pub struct ReservesSkimmed {
+    pub pool: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+}
+
+#[event]
+pub struct SwapExecuted {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub in_mint: Pubkey,
+    pub in_amount: u64,
+    pub out_mint: Pubkey,
+    pub out_amount: u64,
+    pub fee_bps: u16,
+    pub protocol_fee: u64,
+    pub referrer: Option<Pubkey>,
+    pub referral_fee: u64,
+    pub creator_fee: u64,
+}
+
+#[event]
+pub struct PoolStatusChanged {
+    pub pool: Pubkey,
+    pub authority: Pubkey,
+    pub status: PoolStatus,
+}
+
+#[event]
+pub struct EmergencyWithdrawn {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub owner: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+}
+
+#[event]
+pub struct ReferralFeeUpdated {
+    pub pool: Pubkey,
+    pub referral_fee_bps: u16,
+}
+
+#[event]
+pub struct CreatorFeeUpdated {
+    pub pool: Pubkey,
+    pub old: u16,
+    pub new: u16,
+}
+
+#[event]
+pub struct CreatorFeesClaimed {
+    pub pool: Pubkey,
+    pub creator: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+}
+
+#[event]
+pub struct PoolCreatorUpdated {
+    pub pool: Pubkey,
+    pub old: Pubkey,
+    pub new: Pubkey,
+}
+
+#[event]
+pub struct FeeParamsUpdated {
+    pub pool: Pubkey,
+    pub fee_params: DynamicFeeParams,
+}
+
+#[event]
+pub struct FlashLoanRepaid {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+    pub base_fee: u64,
+    pub quote_fee: u64,
+}
+
+#[event]
+pub struct LimitOrderPlaced {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub order: Pubkey,
+    pub bin_id: i32,
+    pub is_ask: bool,
+    pub amount: u64,
+}
+
+#[event]
+pub struct LimitOrderClaimed {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub order: Pubkey,
+    pub converted_amount: u64,
+}
+
+#[event]
+pub struct LimitOrderCancelled {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub order: Pubkey,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+}
+
+#[event]
+pub struct OracleLengthIncreased {
+    pub pool: Pubkey,
+    pub length: u64,
+}
+
+#[error_code]
+pub enum ErrorCode {
+    #[msg("Slippage tolerance exceeded")]
+    SlippageExceeded,
+    #[msg("Pool is paused")]
+    PoolPaused,
+    #[msg("Invalid bin range")]
+    InvalidBinRange,
+    #[msg("Insufficient liquidity")]
+    InsufficientLiquidity,
+    #[msg("Invalid position")]
+    InvalidPosition,
+    #[msg("Invalid bps value")]
+    InvalidBps,
+    #[msg("Invalid bin step")]
+    InvalidBinStep,
+    #[msg("Bin id out of range")]
+    InvalidBinId,
+    #[msg("Math overflow")]
+    MathOverflow,
+    #[msg("Invalid bin array")]
+    InvalidBinArray,
+    #[msg("Zero liquidity")]
+    ZeroLiquidity,
+    #[msg("Invalid liquidity distribution")]
+    InvalidDistribution,
+    #[msg("Invalid fee parameters")]
+    InvalidFeeParams,
+    #[msg("Limit bin is behind the active bin")]
+    InvalidLimitBin,
+    #[msg("Position still holds liquidity or fees")]
+    PositionNotEmpty,
+    #[msg("Signer does not hold the position NFT")]
+    NotPositionHolder,
+    #[msg("Invalid oracle length")]
+    InvalidOracleLength,
+    #[msg("Invalid oracle window")]
+    InvalidOracleWindow,
+    #[msg("Oracle has no price history yet")]
+    OracleNotReady,
+    #[msg("Limit order must sit beyond the active bin")]
+    InvalidOrderBin,
+    #[msg("A flash loan is already outstanding")]
+    FlashLoanActive,
+    #[msg("Flash loan is not repaid in this transaction")]
+    FlashLoanNotRepaid,
+    #[msg("No flash loan to repay")]
+    NoFlashLoan,
+    #[msg("Unauthorized")]
+    Unauthorized,
+    #[msg("Invalid pool status")]
+    InvalidPoolStatus,
+    #[msg("Invalid reward index")]
+    InvalidRewardIndex,
+    #[msg("Reward is already initialized")]
+    RewardAlreadyInitialized,
+    #[msg("Reward is not initialized")]
+    RewardNotInitialized,
+    #[msg("Invalid reward duration")]
+    InvalidRewardDuration,
+    #[msg("Pool is not activated yet")]
+    PoolNotActivated,
+    #[msg("Pool is already activated")]
+    PoolAlreadyActivated,
+    #[msg("Activation point is in the past")]
+    InvalidActivationPoint,
+    #[msg("Trading opens once the alpha vault is filled")]
+    AlphaVaultPending,
+    #[msg("Alpha vault is not filled yet")]
+    AlphaVaultNotFilled,
+    #[msg("Alpha vault is already filled")]
+    AlphaVaultAlreadyFilled,
+    #[msg("Wallet is not on the whitelist")]
+    NotWhitelisted,
+    #[msg("Deposit exceeds the whitelisted cap")]
+    DepositCapExceeded,
+    #[msg("Referrer account must hold the input mint")]
+    InvalidReferrer,
+    #[msg("Pool reserves do not match its balances or vault")]
+    ReserveInvariantViolated,
+    #[msg("Active bin moved past the allowed slippage")]
+    ActiveBinSlippage,
+    #[msg("Alpha vault fill stopped before the price limit")]
+    AlphaVaultUnderfilled,
+}
+
+pub const SCALE_OFFSET: u32 = 64;
+pub const ONE: u128 = 1 << SCALE_OFFSET;
+pub const BASIS_POINT_MAX: u128 = 10000;
+pub const MAX_BIN_ID: i32 = 443_636;
+pub const MIN_BIN_ID: i32 = -443_636;
+
+const MASK_64: u128 = u64::MAX as u128;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Up,
+    Down,
+}
+
+// Bin price in Q64.64, quoted as quote per base: (1 + bin_step / 10000) ^ bin_id
+pub fn get_price_from_id(bin_id: i32, bin_step: u16) -> Result<u128> {
+    require!(
+        (MIN_BIN_ID..=MAX_BIN_ID).contains(&bin_id),
+        ErrorCode::InvalidBinId
+    );
+
+    let step = ((bin_step as u128) << SCALE_OFFSET) / BASIS_POINT_MAX;
+    let base = ONE.checked_add(step).ok_or(ErrorCode::MathOverflow)?;
+    let price = pow(base, bin_id).ok_or(ErrorCode::MathOverflow)?;
+    require!(price > 0, ErrorCode::InvalidBinId);
+
+    Ok(price)
+}
+
+fn pow(base: u128, exp: i32) -> Option<u128> {
+    let mut result = ONE;
+    let mut square = base;
+    let mut e = exp.unsigned_abs();
+
+    while e > 0 {
+        if e & 1 == 1 {
+            result = mul_shr(result, square, Rounding::Down)?;
+        }
+        e >>= 1;
+        if e > 0 {
+            square = mul_shr(square, square, Rounding::Down)?;
+        }
+    }
+
+    if exp < 0 {
+        // 1 / result in Q64.64, i.e. 2^128 / result
+        return u128::MAX.checked_div(result);
+    }
+
+    Some(result)
+}
+
+fn full_mul(x: u128, y: u128) -> (u128, u128) {
+    let (x1, x0) = (x >> 64, x & MASK_64);
+    let (y1, y0) = (y >> 64, y & MASK_64);
+
+    let p00 = x0 * y0;
+    let p01 = x0 * y1;
+    let p10 = x1 * y0;
+    let p11 = x1 * y1;
+
+    let mid = (p00 >> 64) + (p01 & MASK_64) + (p10 & MASK_64);
+    let lo = (p00 & MASK_64) | ((mid & MASK_64) << 64);
+    let hi = p11 + (p01 >> 64) + (p10 >> 64) + (mid >> 64);
+
+    (hi, lo)
+}
+
+// (x * y) >> 64 with a 256-bit intermediate
+pub fn mul_shr(x: u128, y: u128, rounding: Rounding) -> Option<u128> {
+    let (hi, lo) = full_mul(x, y);
+    if hi > MASK_64 {
+        return None;
+    }
+
+    let result = (hi << 64) | (lo >> 64);
+    if rounding == Rounding::Up && lo & MASK_64 != 0 {
+        return result.checked_add(1);
+    }
+
+    Some(result)
+}
+
+// (x << 64) / y
+pub fn shl_div(x: u64, y: u128, rounding: Rounding) -> Option<u128> {
+    if y == 0 {
+        return None;
+    }
+
+    let numerator = (x as u128) << SCALE_OFFSET;
+    let result = numerator / y;
+    if rounding == Rounding::Up && !numerator.is_multiple_of(y) {
+        return result.checked_add(1);
+    }
+
+    Some(result)
+}
+
+// x * y / d with a 256-bit intermediate
+pub fn mul_div(x: u128, y: u128, d: u128, rounding: Rounding) -> Option<u128> {
+    if d == 0 {
+        return None;
+    }
+
+    let (hi, lo) = full_mul(x, y);
+    if hi >= d {
+        return None;
+    }
+
+    // Long division of the 256-bit product by `d`; the quotient fits in 128 bits
+    let mut rem = hi;
+    let mut quotient = 0u128;
+    for i in (0..128).rev() {
+        let carry = rem >> 127;
+        rem = (rem << 1) | ((lo >> i) & 1);
+        quotient <<= 1;
+        if carry == 1 || rem >= d {
+            rem = rem.wrapping_sub(d);
+            quotient |= 1;
+        }
+    }
+
+    if rounding == Rounding::Up && rem != 0 {
+        return quotient.checked_add(1);
+    }
+
+    Some(quotient)
+}
+
+fn to_u64(value: Option<u128>) -> Result<u64> {
+    value
+        .and_then(|v| u64::try_from(v).ok())
+        .ok_or_else(|| error!(ErrorCode::MathOverflow))
+}
+
+// Output received for `amount_in` at `price`
+pub fn get_amount_out(amount_in: u64, price: u128, swap_base_for_quote: bool) -> Result<u64> {
+    if swap_base_for_quote {
+        to_u64(mul_shr(amount_in as u128, price, Rounding::Down))
+    } else {
+        to_u64(shl_div(amount_in, price, Rounding::Down))
+    }
+}
+
+// Input needed to receive `amount_out` at `price`
+pub fn get_amount_in(
+    amount_out: u64,
+    price: u128,
+    swap_base_for_quote: bool,
+    rounding: Rounding,
+) -> Result<u64> {
+    if swap_base_for_quote {
+        to_u64(shl_div(amount_out, price, rounding))
+    } else {
+        to_u64(mul_shr(amount_out as u128, price, rounding))
+    }
+}
+
+// Fee charged on an amount that already includes the fee
+pub fn fee_on_amount_included(amount: u64, fee_bps: u16) -> Result<u64> {
+    require!((fee_bps as u128) < BASIS_POINT_MAX, ErrorCode::InvalidBps);
+    let fee = (amount as u128 * fee_bps as u128).div_ceil(BASIS_POINT_MAX);
+    to_u64(Some(fee))
+}
+
+// Fee to add on top of a net amount so that the total pays `fee_bps`
+pub fn fee_on_amount_excluded(amount: u64, fee_bps: u16) -> Result<u64> {
+    require!((fee_bps as u128) < BASIS_POINT_MAX, ErrorCode::InvalidBps);
+    let denominator = BASIS_POINT_MAX - fee_bps as u128;
+    let fee = (amount as u128 * fee_bps as u128).div_ceil(denominator);
+    to_u64(Some(fee))
+}
+
+#[zero_copy]
+#[derive(Default)]
+pub struct Bin {
+    pub liquidity_supply: u128,
+    pub fee_growth_base_x64: u128,
+    pub fee_growth_quote_x64: u128,
+    pub reward_growth_x64: [u128; NUM_REWARDS],
+    pub amount_base: u64,
+    pub amount_quote: u64,
+    pub ask: OrderSide,
+    pub bid: OrderSide,
+}
+
+// Limit orders resting on one side of a bin. `amount` is still tradeable and
+// `filled` holds what swaps paid for it, which only the orders can withdraw.
+#[zero_copy]
+#[derive(Default)]
+pub struct OrderSide {
+    pub factor_x64: u128,
+    pub amount: u64,
+    pub filled: u64,
+    pub generation: u64,
+    pub _padding: [u8; 8],
+}
+
+impl OrderSide {
+    pub const LEN: usize = 16 + 8 + 8 + 8 + 8;
+
+    pub fn place(&mut self, amount: u64) -> Result<(u64, u128)> {
+        if self.amount == 0 || self.factor_x64 == 0 {
+            self.generation += 1;
+            self.factor_x64 = ONE;
+        }
+        self.amount = self.amount.checked_add(amount).ok_or(ErrorCode::MathOverflow)?;
+        Ok((self.generation, self.factor_x64))
+    }
+
+    pub fn withdraw(&mut self, amount: u64) -> u64 {
+        let amount = amount.min(self.amount);
+        self.amount -= amount;
+        amount
+    }
+
+    fn fill(&mut self, amount_out: u64, amount_in: u64) -> Result<()> {
+        self.factor_x64 = mul_div(
+            self.factor_x64,
+            (self.amount - amount_out) as u128,
+            self.amount as u128,
+            Rounding::Down,
+        )
+        .ok_or(ErrorCode::MathOverflow)?;
+        self.amount -= amount_out;
+        self.filled = self.filled.checked_add(amount_in).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct BinSwap {
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub fee: u64,
+    pub protocol_fee: u64,
+    // LP part of the fee taken while the bin had no LP shares to credit
+    pub unowned_lp_fee: u64,
+    // Part of the swap filled by limit orders, net of the fee
+    pub order_in: u64,
+    pub order_out: u64,
+}
+
+impl Bin {
+    pub const LEN: usize = 16 + 16 + 16 + 16 * NUM_REWARDS + 8 + 8 + OrderSide::LEN * 2;
+
+    pub fn order_side_mut(&mut self, is_ask: bool) -> &mut OrderSide {
+        if is_ask {
+            &mut self.ask
+        } else {
+            &mut self.bid
+        }
+    }
+
+    // Bin value in quote units, used to price liquidity shares
+    pub fn liquidity(amount_base: u64, amount_quote: u64, price: u128) -> Result<u128> {
+        mul_shr(amount_base as u128, price, Rounding::Down)
+            .and_then(|base_value| base_value.checked_add(amount_quote as u128))
+            .ok_or_else(|| error!(ErrorCode::MathOverflow))
+    }
+
+    pub fn deposit(&mut self, amount_base: u64, amount_quote: u64, price: u128) -> Result<u128> {
+        let liquidity = Bin::liquidity(amount_base, amount_quote, price)?;
+        let bin_liquidity = Bin::liquidity(self.amount_base, self.amount_quote, price)?;
+
+        let shares = if self.liquidity_supply == 0 || bin_liquidity == 0 {
+            liquidity
+        } else {
+            mul_div(liquidity, self.liquidity_supply, bin_liquidity, Rounding::Down)
+                .ok_or(ErrorCode::MathOverflow)?
+        };
+        if shares == 0 {
+            return Ok(0);
+        }
+
+        self.amount_base = self.amount_base.checked_add(amount_base).ok_or(ErrorCode::MathOverflow)?;
+        self.amount_quote = self.amount_quote.checked_add(amount_quote).ok_or(ErrorCode::MathOverflow)?;
+        self.liquidity_supply = self.liquidity_supply.checked_add(shares).ok_or(ErrorCode::MathOverflow)?;
+
+        Ok(shares)
+    }
+
+    pub fn withdraw(&mut self, shares: u128) -> Result<(u64, u64)> {
+        require!(shares <= self.liquidity_supply, ErrorCode::InsufficientLiquidity);
+        if shares == 0 {
+            return Ok((0, 0));
+        }
+
+        let amount_base = to_u64(mul_div(self.amount_base as u128, shares, self.liquidity_supply, Rounding::Down))?;
+        let amount_quote = to_u64(mul_div(self.amount_quote as u128, shares, self.liquidity_supply, Rounding::Down))?;
+
+        self.amount_base -= amount_base;
+        self.amount_quote -= amount_quote;
+        self.liquidity_supply -= shares;
+
+        Ok((amount_base, amount_quote))
+    }
+
+    pub fn is_empty(&self, swap_base_for_quote: bool) -> bool {
+        self.max_amount_out(swap_base_for_quote) == 0
+    }
+
+    // Fills as much of `amount_in` (fee inclusive) as the bin can take at `price`
+    pub fn swap_exact_in(
+        &mut self,
+        amount_in: u64,
+        price: u128,
+        fee_bps: u16,
+        protocol_fee_bps: u16,
+        swap_base_for_quote: bool,
+    ) -> Result<BinSwap> {
+        let max_out = self.max_amount_out(swap_base_for_quote);
+        let max_in = get_amount_in(max_out, price, swap_base_for_quote, Rounding::Up)?;
+        let max_fee = fee_on_amount_excluded(max_in, fee_bps)?;
+        let max_in_with_fee = max_in.checked_add(max_fee).ok_or(ErrorCode::MathOverflow)?;
+
+        if amount_in >= max_in_with_fee {
+            return self.apply_swap(max_in, max_fee, max_out, protocol_fee_bps, swap_base_for_quote);
+        }
+
+        let fee = fee_on_amount_included(amount_in, fee_bps)?;
+        let amount_in_net = amount_in - fee;
+        let amount_out = get_amount_out(amount_in_net, price, swap_base_for_quote)?.min(max_out);
+        self.apply_swap(amount_in_net, fee, amount_out, protocol_fee_bps, swap_base_for_quote)
+    }
+
+    // Works back from as much of `amount_out` as the bin holds to the input
+    // it costs, rounding the input and the fee up
+    pub fn swap_exact_out(
+        &mut self,
+        amount_out: u64,
+        price: u128,
+        fee_bps: u16,
+        protocol_fee_bps: u16,
+        swap_base_for_quote: bool,
+    ) -> Result<BinSwap> {
+        let amount_out = amount_out.min(self.max_amount_out(swap_base_for_quote));
+        let amount_in_net = get_amount_in(amount_out, price, swap_base_for_quote, Rounding::Up)?;
+        let fee = fee_on_amount_excluded(amount_in_net, fee_bps)?;
+        self.apply_swap(amount_in_net, fee, amount_out, protocol_fee_bps, swap_base_for_quote)
+    }
+
+    // LP reserves plus the limit orders selling the output token
+    fn max_amount_out(&self, swap_base_for_quote: bool) -> u64 {
+        if swap_base_for_quote {
+            self.amount_quote.saturating_add(self.bid.amount)
+        } else {
+            self.amount_base.saturating_add(self.ask.amount)
+        }
+    }
+
+    fn apply_swap(
+        &mut self,
+        amount_in_net: u64,
+        fee: u64,
+        amount_out: u64,
+        protocol_fee_bps: u16,
+        swap_base_for_quote: bool,
+    ) -> Result<BinSwap> {
+        let protocol_fee = (fee as u128 * protocol_fee_bps as u128 / BASIS_POINT_MAX) as u64;
+
+        // LP fees stay in the vault outside the bin reserves and are owed to
+        // the bin's shareholders through its fee growth. A bin holding only
+        // limit orders has no shareholders, so that part goes to the protocol.
+        let (lp_fee_growth, unowned_lp_fee) = if self.liquidity_supply > 0 {
+            let growth = shl_div(fee - protocol_fee, self.liquidity_supply, Rounding::Down)
+                .ok_or(ErrorCode::MathOverflow)?;
+            (growth, 0)
+        } else {
+            (0, fee - protocol_fee)
+        };
+
+        // Limit orders fill first and their share of the input is set aside
+        // for them rather than added back to the tradeable reserves
+        let orders = if swap_base_for_quote { &mut self.bid } else { &mut self.ask };
+        let order_out = amount_out.min(orders.amount);
+        let order_in = if order_out > 0 {
+            to_u64(mul_div(
+                amount_in_net as u128,
+                order_out as u128,
+                amount_out as u128,
+                Rounding::Down,
+            ))?
+        } else {
+            0
+        };
+        if order_out > 0 {
+            orders.fill(order_out, order_in)?;
+        }
+        let lp_in = amount_in_net - order_in;
+        let lp_out = amount_out - order_out;
+
+        if swap_base_for_quote {
+            self.amount_base = self.amount_base.checked_add(lp_in).ok_or(ErrorCode::MathOverflow)?;
+            self.amount_quote -= lp_out;
+            self.fee_growth_base_x64 = self.fee_growth_base_x64.wrapping_add(lp_fee_growth);
+        } else {
+            self.amount_quote = self.amount_quote.checked_add(lp_in).ok_or(ErrorCode::MathOverflow)?;
+            self.amount_base -= lp_out;
+            self.fee_growth_quote_x64 = self.fee_growth_quote_x64.wrapping_add(lp_fee_growth);
+        }
+
+        Ok(BinSwap {
+            amount_in: amount_in_net.checked_add(fee).ok_or(ErrorCode::MathOverflow)?,
+            amount_out,
+            fee,
+            protocol_fee,
+            unowned_lp_fee,
+            order_in,
+            order_out,
+        })
+    }
+}
+
+pub trait BinStore {
+    fn bin_mut(&mut self, bin_id: i32) -> Option<&mut Bin>;
+}
+
+// Bin arrays loaded for writing, or copied out of read-only accounts when
+// simulating a swap
+pub struct BinArrays<T> {
+    arrays: Vec<T>,
+}
+
+impl<'a> BinArrays<RefMut<'a, BinArray>> {
+    pub fn load_mut<'info>(loaders: &'a [AccountLoader<'info, BinArray>]) -> Result<Self> {
+        let arrays = loaders
+            .iter()
+            .map(|loader| loader.load_mut())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { arrays })
+    }
+}
+
+impl BinArrays<Box<BinArray>> {
+    // Copies straight into heap memory; a BinArray does not fit in an SBF
+    // stack frame
+    pub fn load_copy<'info>(loaders: &[AccountLoader<'info, BinArray>]) -> Result<Self> {
+        let arrays = loaders
+            .iter()
+            .map(|loader| {
+                let mut array = bytemuck::zeroed_box::<BinArray>();
+                let source = loader.load()?;
+                bytemuck::bytes_of_mut(array.as_mut()).copy_from_slice(bytemuck::bytes_of(&*source));
+                Ok(array)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { arrays })
+    }
+}
+
+impl<T: DerefMut<Target = BinArray>> BinArrays<T> {
+    pub fn bin(&mut self, bin_id: i32) -> Result<&mut Bin> {
+        self.bin_mut(bin_id).ok_or_else(|| error!(ErrorCode::InvalidBinArray))
+    }
+}
+
+impl<T: DerefMut<Target = BinArray>> BinStore for BinArrays<T> {
+    fn bin_mut(&mut self, bin_id: i32) -> Option<&mut Bin> {
+        self.arrays
+            .iter_mut()
+            .find(|array| array.contains(bin_id))
+            .and_then(|array| array.bin_mut(bin_id).ok())
+    }
+}
+
+// A position's range spans at most two bin arrays, which may be the same account
+pub fn bin_array_pair<'info>(
+    lower: &AccountLoader<'info, BinArray>,
+    upper: &AccountLoader<'info, BinArray>,
+) -> Vec<AccountLoader<'info, BinArray>> {
+    if lower.key() == upper.key() {
+        vec![lower.clone()]
+    } else {
+        vec![lower.clone(), upper.clone()]
+    }
+}
+
+// Bin arrays for a swap are passed as remaining accounts, ordered in the swap direction
+pub fn load_bin_arrays<'info>(
+    accounts: &'info [AccountInfo<'info>],
+    pool: &Pubkey,
+) -> Result<Vec<AccountLoader<'info, BinArray>>> {
+    accounts
+        .iter()
+        .map(|info| {
+            let loader = AccountLoader::<BinArray>::try_from(info)?;
+            require_keys_eq!(loader.load()?.pool, *pool, ErrorCode::InvalidBinArray);
+            Ok(loader)
+        })
+        .collect()
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub enum SwapAmount {
+    ExactIn(u64),
+    ExactOut(u64),
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct SwapResult {
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub fee: u64,
+    pub protocol_fee: u64,
+    pub creator_fee: u64,
+    pub bins_crossed: u32,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct SwapQuote {
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub fee: u64,
+    pub protocol_fee: u64,
+    pub fee_bps: u16,
+    pub bins_crossed: u32,
+    pub active_bin_id: i32,
+}
+
+impl SwapResult {
+    // Average fee rate actually paid across all bins
+    pub fn fee_bps(&self) -> u16 {
+        if self.amount_in == 0 {
+            return 0;
+        }
+        (self.fee as u128 * BASIS_POINT_MAX / self.amount_in as u128) as u16
+    }
+}
+
+impl Pool {
+    // Walks bins from the active bin in the swap direction until the exact
+    // side is used up, the available liquidity runs out or the next bin would
+    // pass `limit_bin_id`, moving the active bin along. `fee_discount_bps`
+    // takes that share off the fee charged in every bin.
+    #[allow(clippy::too_many_arguments)]
+    pub fn swap<S: BinStore>(
+        &mut self,
+        bins: &mut S,
+        amount: SwapAmount,
+        base_fee_bps: u16,
+        fee_discount_bps: u16,
+        swap_base_for_quote: bool,
+        limit_bin_id: Option<i32>,
+        now: i64,
+    ) -> Result<SwapResult> {
+        if let Some(limit) = limit_bin_id {
+            require!(
+                if swap_base_for_quote {
+                    limit <= self.active_bin_id
+                } else {
+                    limit >= self.active_bin_id
+                },
+                ErrorCode::InvalidLimitBin
+            );
+        }
+
+        self.update_rewards(bins, now)?;
+
+        let mut result = SwapResult::default();
+        let mut amount_left = match amount {
+            SwapAmount::ExactIn(amount_in) => amount_in,
+            SwapAmount::ExactOut(amount_out) => amount_out,
+        };
+
+        if self.dynamic_fee_enabled {
+            self.update_references(now);
+        }
+
+        while amount_left > 0 {
+            if self.dynamic_fee_enabled {
+                self.update_volatility_accumulator();
+            }
+            let fee_bps = self.total_fee_bps(base_fee_bps);
+            let fee_bps = fee_bps - (fee_bps as u32 * fee_discount_bps as u32 / BASIS_POINT_MAX as u32) as u16;
+
+            let Some(bin) = bins.bin_mut(self.active_bin_id) else {
+                break;
+            };
+
+            if !bin.is_empty(swap_base_for_quote) {
+                let price = get_price_from_id(self.active_bin_id, self.bin_step)?;
+                // The bin sets aside the protocol and creator cut together
+                let non_lp_fee_bps = self.protocol_fee_bps + self.creator_fee_bps;
+                let step = match amount {
+                    SwapAmount::ExactIn(_) => bin.swap_exact_in(
+                        amount_left,
+                        price,
+                        fee_bps,
+                        non_lp_fee_bps,
+                        swap_base_for_quote,
+                    )?,
+                    SwapAmount::ExactOut(_) => bin.swap_exact_out(
+                        amount_left,
+                        price,
+                        fee_bps,
+                        non_lp_fee_bps,
+                        swap_base_for_quote,
+                    )?,
+                };
+                let creator_fee = if non_lp_fee_bps > 0 {
+                    to_u64(mul_div(
+                        step.protocol_fee as u128,
+                        self.creator_fee_bps as u128,
+                        non_lp_fee_bps as u128,
+                        Rounding::Down,
+                    ))?
+                } else {
+                    0
+                };
+
+                amount_left -= match amount {
+                    SwapAmount::ExactIn(_) => step.amount_in,
+                    SwapAmount::ExactOut(_) => step.amount_out,
+                };
+                result.amount_in += step.amount_in;
+                result.amount_out += step.amount_out;
+                result.fee += step.fee;
+                result.protocol_fee += step.protocol_fee - creator_fee + step.unowned_lp_fee;
+                result.creator_fee += creator_fee;
+
+                // Filled orders hand over the output token for the input, and
+                // the rest of the input net of fees goes to the bin reserves
+                let lp_in = step.amount_in - step.fee - step.order_in;
+                let lp_out = step.amount_out - step.order_out;
+                let lp_fee = step.fee - step.protocol_fee - step.unowned_lp_fee;
+                if swap_base_for_quote {
+                    self.order_quote -= step.order_out;
+                    self.order_base += step.order_in;
+                    self.liquidity_quote -= lp_out;
+                    self.liquidity_base += lp_in;
+                    self.lp_fee_base += lp_fee;
+                } else {
+                    self.order_base -= step.order_out;
+                    self.order_quote += step.order_in;
+                    self.liquidity_base -= lp_out;
+                    self.liquidity_quote += lp_in;
+                    self.lp_fee_quote += lp_fee;
+                }
+
+                if amount_left == 0 {
+                    break;
+                }
+            }
+
+            let next_bin_id = if swap_base_for_quote {
+                self.active_bin_id - 1
+            } else {
+                self.active_bin_id + 1
+            };
+            if !(MIN_BIN_ID..=MAX_BIN_ID).contains(&next_bin_id) {
+                break;
+            }
+            if let Some(limit) = limit_bin_id {
+                let past_limit = if swap_base_for_quote {
+                    next_bin_id < limit
+                } else {
+                    next_bin_id > limit
+                };
+                if past_limit {
+                    break;
+                }
+            }
+            // Stay on the last loaded bin rather than step into an array the
+            // swap was not given
+            if bins.bin_mut(next_bin_id).is_none() {
+                break;
+            }
+            self.active_bin_id = next_bin_id;
+            result.bins_crossed += 1;
+        }
+
+        self.volatility.last_update_timestamp = now;
+
+        Ok(result)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn within(actual: u128, expected: u128, tolerance: u128) -> bool {
+        actual.abs_diff(expected) <= tolerance
+    }
+
+    #[test]
+    fn price_at_bin_zero_is_one() {
+        for bin_step in [1, 25, 100] {
+            assert_eq!(get_price_from_id(0, bin_step).unwrap(), ONE);
+        }
+    }
+
+    #[test]
+    fn price_bounds() {
+        let max = get_price_from_id(MAX_BIN_ID, 1).unwrap();
+        let min = get_price_from_id(MIN_BIN_ID, 1).unwrap();
+        assert!(max > ONE << 63);
+        assert_eq!(min, 1);
+        assert!(get_price_from_id(MAX_BIN_ID + 1, 1).is_err());
+        assert!(get_price_from_id(MIN_BIN_ID - 1, 1).is_err());
+    }
+
+    #[test]
+    fn price_increases_with_bin_id() {
+        for bin_id in [-100_000, -1000, -1, 0, 1, 1000, MAX_BIN_ID - 1] {
+            assert!(get_price_from_id(bin_id + 1, 1).unwrap() > get_price_from_id(bin_id, 1).unwrap());
+        }
+        // The lowest prices round down to the same Q64.64 value
+        assert_eq!(get_price_from_id(MIN_BIN_ID, 1).unwrap(), 1);
+        assert_eq!(get_price_from_id(MIN_BIN_ID + 1, 1).unwrap(), 1);
+    }
+
+    #[test]
+    fn price_round_trip() {
+        // p(id) * p(-id) comes back to one
+        for (bin_id, bin_step) in [(1, 100), (1000, 25), (10_000, 10), (100_000, 1)] {
+            let up = get_price_from_id(bin_id, bin_step).unwrap();
+            let down = get_price_from_id(-bin_id, bin_step).unwrap();
+            assert!(within(mul_shr(up, down, Rounding::Down).unwrap(), ONE, ONE >> 40));
+        }
+
+        // At the edges the price is too small or large to invert precisely,
+        // but converting an amount there and back never gains
+        for bin_id in [MIN_BIN_ID, MIN_BIN_ID + 1, MAX_BIN_ID - 1, MAX_BIN_ID] {
+            let price = get_price_from_id(bin_id, 1).unwrap();
+            for swap_base_for_quote in [true, false] {
+                let Ok(amount_out) = get_amount_out(1_000_000, price, swap_base_for_quote) else {
+                    continue;
+                };
+                if amount_out == 0 {
+                    continue;
+                }
+                let amount_in = get_amount_in(amount_out, price, swap_base_for_quote, Rounding::Up).unwrap();
+                assert!(amount_in <= 1_000_000);
+                assert!(get_amount_out(amount_in, price, swap_base_for_quote).unwrap() >= amount_out);
+            }
+        }
+    }
+
+    #[test]
+    fn larger_bin_steps_overflow_at_the_edges() {
+        assert!(get_price_from_id(MAX_BIN_ID, 100).is_err());
+    }
+
+    #[test]
+    fn pow_rounds_down() {
+        assert_eq!(pow(3 * ONE, 0), Some(ONE));
+        assert_eq!(pow(3 * ONE, 1), Some(3 * ONE));
+        assert_eq!(pow(2 * ONE, 10), Some(ONE << 10));
+        // 2^128 / 2^65 computed as u128::MAX / 2^65
+        assert_eq!(pow(2 * ONE, -1), Some(ONE / 2 - 1));
+        assert_eq!(pow(2 * ONE, 64), None);
+    }
+
+    #[test]
+    fn mul_div_rounding() {
+        assert_eq!(mul_div(10, 10, 3, Rounding::Down), Some(33));
+        assert_eq!(mul_div(10, 10, 3, Rounding::Up), Some(34));
+        assert_eq!(mul_div(6, 10, 3, Rounding::Up), Some(20));
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX, Rounding::Down), Some(u128::MAX));
+        assert_eq!(mul_div(u128::MAX, 2, 1, Rounding::Down), None);
+        assert_eq!(mul_div(1, 1, 0, Rounding::Down), None);
+    }
+
+    #[test]
+    fn mul_shr_and_shl_div_rounding() {
+        assert_eq!(mul_shr(ONE, ONE, Rounding::Down), Some(ONE));
+        assert_eq!(mul_shr(3, ONE / 2, Rounding::Down), Some(1));
+        assert_eq!(mul_shr(3, ONE / 2, Rounding::Up), Some(2));
+        assert_eq!(mul_shr(u128::MAX, u128::MAX, Rounding::Down), None);
+        assert_eq!(shl_div(1, 3 * ONE, Rounding::Down), Some(0));
+        assert_eq!(shl_div(1, 3 * ONE, Rounding::Up), Some(1));
+        assert_eq!(shl_div(1, 0, Rounding::Down), None);
+    }
+
+    fn quote_bin(amount_quote: u64) -> Bin {
+        let mut bin = Bin::default();
+        bin.deposit(0, amount_quote, ONE).unwrap();
+        bin
+    }
+
+    #[test]
+    fn swap_exact_in_within_bin() {
+        let mut bin = quote_bin(1_000_000);
+        let step = bin.swap_exact_in(10_000, ONE, 100, 2000, true).unwrap();
+
+        assert_eq!(step.amount_in, 10_000);
+        assert_eq!(step.fee, 100);
+        assert_eq!(step.protocol_fee, 20);
+        assert_eq!(step.unowned_lp_fee, 0);
+        assert_eq!(step.amount_out, 9_900);
+        assert_eq!(bin.amount_base, 9_900);
+        assert_eq!(bin.amount_quote, 990_100);
+        assert_eq!(bin.fee_growth_base_x64, shl_div(80, 1_000_000, Rounding::Down).unwrap());
+    }
+
+    #[test]
+    fn swap_exact_in_empties_bin() {
+        let mut bin = quote_bin(1_000_000);
+        let step = bin.swap_exact_in(10_000_000, ONE, 100, 0, true).unwrap();
+
+        assert_eq!(step.amount_out, 1_000_000);
+        // The fee on the net input is rounded up
+        assert_eq!(step.fee, 10_102);
+        assert_eq!(step.amount_in, 1_010_102);
+        assert!(bin.is_empty(true));
+    }
+
+    fn bin_arrays(indexes: &[i64]) -> BinArrays<Box<BinArray>> {
+        let arrays = indexes
+            .iter()
+            .map(|index| {
+                let mut array = bytemuck::zeroed_box::<BinArray>();
+                array.index = *index;
+                array
+            })
+            .collect();
+        BinArrays { arrays }
+    }
 
-    pub token_program: Program<'info, Token>,
-}
+    fn test_pool(active_bin_id: i32) -> Pool {
+        Pool {
+            active_bin_id,
+            bin_step: 10,
+            ..Default::default()
+        }
+    }
 
-#[derive(Accounts)]
-pub struct Swap<'info> {
-    #[account(
-        mut,
-        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+    fn seed_quote(bins: &mut BinArrays<Box<BinArray>>, pool: &mut Pool, bin_ids: &[i32], amount: u64) {
+        for bin_id in bin_ids {
+            let price = get_price_from_id(*bin_id, pool.bin_step).unwrap();
+            bins.bin(*bin_id).unwrap().deposit(0, amount, price).unwrap();
+            pool.liquidity_quote += amount;
+        }
+    }
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+    #[test]
+    fn swap_walks_across_bins_and_arrays() {
+        let mut pool = test_pool(1);
+        let mut bins = bin_arrays(&[0, -1]);
+        seed_quote(&mut bins, &mut pool, &[1, 0, -1, -2], 1_000);
 
-    #[account(mut)]
-    pub user_base_account: Account<'info, TokenAccount>,
+        let expected_in: u64 = [1, 0, -1, -2]
+            .iter()
+            .map(|id| get_amount_in(1_000, get_price_from_id(*id, 10).unwrap(), true, Rounding::Up).unwrap())
+            .sum();
+        let result = pool.swap(&mut bins, SwapAmount::ExactIn(expected_in), 0, 0, true, None, 0).unwrap();
 
-    #[account(mut)]
-    pub user_quote_account: Account<'info, TokenAccount>,
+        assert_eq!(result.amount_out, 4_000);
+        assert_eq!(result.amount_in, expected_in);
+        assert_eq!(pool.active_bin_id, -2);
+        assert_eq!(result.bins_crossed, 3);
+        assert_eq!(pool.liquidity_quote, 0);
+    }
 
-    #[account(mut)]
-    pub base_vault: Account<'info, TokenAccount>,
+    #[test]
+    fn swap_stops_on_the_last_loaded_bin() {
+        let mut pool = test_pool(0);
+        let mut bins = bin_arrays(&[0, -1]);
+        seed_quote(&mut bins, &mut pool, &[0, -1], 1_000);
 
-    #[account(mut)]
-    pub quote_vault: Account<'info, TokenAccount>,
+        let result = pool.swap(&mut bins, SwapAmount::ExactIn(1_000_000), 0, 0, true, None, 0).unwrap();
 
-    pub token_program: Program<'info, Token>,
-}
+        assert_eq!(result.amount_out, 2_000);
+        // Bin -53 starts array -2, which was not passed in
+        assert_eq!(pool.active_bin_id, -(MAX_BIN_PER_ARRAY as i32));
+        assert_eq!(result.bins_crossed, MAX_BIN_PER_ARRAY as u32);
+    }
 
-#[derive(Accounts)]
-pub struct PausePool<'info> {
-    #[account(
-        mut,
-        seeds = [b"pool", pool.base_mint.as_ref(), pool.quote_mint.as_ref(), &pool.bin_step.to_le_bytes()],
-        bump = pool.bump
-    )]
-    pub pool: Account<'info, Pool>,
+    fn split(shape: LiquidityShape, base_amount: u64, quote_amount: u64) -> Vec<(i32, u64, u64)> {
+        distribute_liquidity(&shape, -2, 2, 0, base_amount, quote_amount).unwrap()
+    }
 
-    /// CHECK: Admin authority
-    pub admin: Signer<'info>,
-}
+    #[test]
+    fn spot_spreads_evenly_on_each_side() {
+        assert_eq!(
+            split(LiquidityShape::Spot, 300, 300),
+            vec![(-2, 0, 100), (-1, 0, 100), (0, 100, 100), (1, 100, 0), (2, 100, 0)]
+        );
+    }
 
-#[account]
-pub struct Pool {
-    pub factory: Pubkey,
-    pub creator: Pubkey,
-    pub base_mint: Pubkey,
-    pub quote_mint: Pubkey,
-    pub base_vault: Pubkey,
-    pub quote_vault: Pubkey,
-    pub base_fee_bps: u16,
-    pub bin_step: u16,
-    pub active_bin_id: i32,
-    pub protocol_fee_bps: u16,
-    pub total_volume: u64,
-    pub total_fees: u64,
-    pub paused: bool,
-    pub bump: u8,
-}
+    #[test]
+    fn curve_peaks_at_the_active_bin() {
+        assert_eq!(
+            split(LiquidityShape::Curve, 600, 600),
+            vec![(-2, 0, 100), (-1, 0, 200), (0, 300, 300), (1, 200, 0), (2, 100, 0)]
+        );
+    }
 
-impl Pool {
-    pub const LEN: usize = 32 + 32 + 32 + 32 + 32 + 32 + 2 + 2 + 4 + 2 + 8 + 8 + 1 + 1;
-}
+    #[test]
+    fn bid_ask_weights_the_edges() {
+        assert_eq!(
+            split(LiquidityShape::BidAsk, 600, 600),
+            vec![(-2, 0, 300), (-1, 0, 200), (0, 100, 100), (1, 200, 0), (2, 300, 0)]
+        );
+    }
 
-#[account]
-pub struct Position {
-    pub owner: Pubkey,
-    pub pool: Pubkey,
-    pub lower_bin_id: i32,
-    pub upper_bin_id: i32,
-    pub base_amount: u64,
-    pub quote_amount: u64,
-    pub fee_debt_base: u64,
-    pub fee_debt_quote: u64,
-}
+    #[test]
+    fn distribution_rounds_down() {
+        let bins = split(LiquidityShape::Spot, 100, 0);
+        assert_eq!(bins.iter().map(|(_, base, _)| base).sum::<u64>(), 99);
+    }
 
-impl Position {
-    pub const LEN: usize = 32 + 32 + 4 + 4 + 8 + 8 + 8 + 8;
-}
+    #[test]
+    fn custom_weights_must_cover_the_range() {
+        let shape = LiquidityShape::Custom { weights: vec![1, 1] };
+        assert!(distribute_liquidity(&shape, -2, 2, 0, 100, 100).is_err());
 
-#[event]
-pub struct LiquidityAdded {
-    pub pool: Pubkey,
-    pub user: Pubkey,
-    pub bin_id: i32,
-    pub base_amount: u64,
-    pub quote_amount: u64,
-}
+        let shape = LiquidityShape::Custom { weights: vec![0, 1, 0, 0, 1] };
+        assert_eq!(
+            distribute_liquidity(&shape, -2, 2, 0, 100, 100).unwrap(),
+            vec![(-2, 0, 0), (-1, 0, 100), (0, 0, 0), (1, 0, 0), (2, 100, 0)]
+        );
+    }
 
-#[event]
-pub struct LiquidityRemoved {
-    pub pool: Pubkey,
-    pub user: Pubkey,
-    pub base_amount: u64,
-    pub quote_amount: u64,
-}
+    #[test]
+    fn one_sided_range_rejects_the_other_token() {
+        // Every bin is above the active bin, so there is nowhere to put quote
+        assert!(distribute_liquidity(&LiquidityShape::Spot, 1, 3, 0, 100, 100).is_err());
+        assert!(distribute_liquidity(&LiquidityShape::Spot, 1, 3, 0, 100, 0).is_ok());
+    }
 
-#[event]
-pub struct SwapExecuted {
-    pub pool: Pubkey,
-    pub user: Pubkey,
-    pub in_mint: Pubkey,
-    pub in_amount: u64,
-    pub out_mint: Pubkey,
-    pub out_amount: u64,
-    pub fee_bps: u16,
-    pub protocol_fee: u64,
-}
+    fn dynamic_pool(active_bin_id: i32) -> Pool {
+        Pool {
+            dynamic_fee_enabled: true,
+            fee_params: DynamicFeeParams {
+                filter_period: 30,
+                decay_period: 600,
+                reduction_factor: 5_000,
+                variable_fee_control: 4_000_000,
+                max_volatility_accumulator: 350_000,
+                max_fee_bps: 500,
+            },
+            ..test_pool(active_bin_id)
+        }
+    }
 
-#[error_code]
-pub enum ErrorCode {
-    #[msg("Slippage tolerance exceeded")]
-    SlippageExceeded,
-    #[msg("Pool is paused")]
-    PoolPaused,
-    #[msg("Invalid bin range")]
-    InvalidBinRange,
-    #[msg("Insufficient liquidity")]
-    InsufficientLiquidity,
-    #[msg("Invalid position")]
-    InvalidPosition,
-    #[msg("Invalid bps value")]
-    InvalidBps,
-}
+    #[test]
+    fn static_fee_ignores_volatility() {
+        let mut pool = dynamic_pool(0);
+        pool.dynamic_fee_enabled = false;
+        pool.volatility.volatility_accumulator = 350_000;
+        assert_eq!(pool.total_fee_bps(25), 25);
+    }
+
+    #[test]
+    fn variable_fee_grows_with_bins_moved() {
+        let mut pool = dynamic_pool(3);
+        pool.update_volatility_accumulator();
+        assert_eq!(pool.volatility.volatility_accumulator, 30_000);
+        // (30_000 * 10)^2 * 4_000_000 / 1e16
+        assert_eq!(pool.variable_fee_bps(), 36);
+        assert_eq!(pool.total_fee_bps(25), 61);
+
+        pool.active_bin_id = 100;
+        pool.update_volatility_accumulator();
+        assert_eq!(pool.volatility.volatility_accumulator, 350_000);
+        assert_eq!(pool.total_fee_bps(25), 500);
+    }
+
+    #[test]
+    fn references_follow_filter_and_decay_periods() {
+        let mut pool = dynamic_pool(5);
+        pool.volatility.volatility_accumulator = 40_000;
+        pool.volatility.volatility_reference = 7;
+        pool.volatility.last_update_timestamp = 1_000;
+
+        // Inside the filter period nothing moves
+        pool.update_references(1_029);
+        assert_eq!(pool.volatility.index_reference, 0);
+        assert_eq!(pool.volatility.volatility_reference, 7);
+
+        // Between filter and decay half the accumulator carries over
+        pool.update_references(1_030);
+        assert_eq!(pool.volatility.index_reference, 5);
+        assert_eq!(pool.volatility.volatility_reference, 20_000);
+
+        // Idle past the decay period resets it
+        pool.update_references(1_600);
+        assert_eq!(pool.volatility.volatility_reference, 0);
+    }
+
+    #[test]
+    fn swap_accumulates_volatility_per_bin_crossed() {
+        let mut pool = dynamic_pool(1);
+        let mut bins = bin_arrays(&[0, -1]);
+        seed_quote(&mut bins, &mut pool, &[1, 0, -1, -2], 1_000);
+
+        let result = pool.swap(&mut bins, SwapAmount::ExactOut(4_000), 25, 0, true, None, 100).unwrap();
+        assert_eq!(result.amount_out, 4_000);
+        assert_eq!(pool.volatility.volatility_accumulator, 30_000);
+        assert_eq!(pool.volatility.last_update_timestamp, 100);
+        // Later bins pay the variable fee on top of the base fee
+        assert!(result.fee_bps() > 25);
+    }
+
+    #[test]
+    fn dynamic_fee_params_stay_within_the_factory_cap() {
+        let params = dynamic_pool(0).fee_params;
+        assert!(params.validate(25, 500).is_ok());
+        assert!(params.validate(25, 499).is_err());
+        assert!(params.validate(501, 1_000).is_err());
+    }
+
+    fn scheduled_pool(mode: FeeScheduleMode, unit: ScheduleUnit, reduction_factor: u16) -> Pool {
+        Pool {
+            base_fee_bps: 25,
+            fee_schedule: FeeSchedule {
+                mode,
+                unit,
+                cliff_fee_bps: 1_000,
+                number_of_periods: 5,
+                period_frequency: 10,
+                reduction_factor,
+            },
+            fee_schedule_start: 100,
+            ..test_pool(0)
+        }
+    }
+
+    #[test]
+    fn linear_schedule_steps_down_each_period() {
+        let pool = scheduled_pool(FeeScheduleMode::Linear, ScheduleUnit::Slot, 150);
+        assert_eq!(pool.scheduled_fee_bps(50, 0).unwrap(), 1_000);
+        assert_eq!(pool.scheduled_fee_bps(100, 0).unwrap(), 1_000);
+        assert_eq!(pool.scheduled_fee_bps(109, 0).unwrap(), 1_000);
+        assert_eq!(pool.scheduled_fee_bps(110, 0).unwrap(), 850);
+        assert_eq!(pool.scheduled_fee_bps(149, 0).unwrap(), 400);
+        assert_eq!(pool.scheduled_fee_bps(150, 0).unwrap(), 25);
+    }
+
+    #[test]
+    fn linear_schedule_never_drops_below_the_base_fee() {
+        let pool = scheduled_pool(FeeScheduleMode::Linear, ScheduleUnit::Slot, 300);
+        assert_eq!(pool.scheduled_fee_bps(140, 0).unwrap(), 25);
+    }
+
+    #[test]
+    fn exponential_schedule_halves_each_period() {
+        let pool = scheduled_pool(FeeScheduleMode::Exponential, ScheduleUnit::Timestamp, 5_000);
+        // Timestamp schedules ignore the slot
+        assert_eq!(pool.scheduled_fee_bps(0, 105).unwrap(), 1_000);
+        assert_eq!(pool.scheduled_fee_bps(0, 110).unwrap(), 500);
+        assert_eq!(pool.scheduled_fee_bps(0, 120).unwrap(), 250);
+        assert_eq!(pool.scheduled_fee_bps(0, 140).unwrap(), 62);
+        assert_eq!(pool.scheduled_fee_bps(0, 150).unwrap(), 25);
+    }
+
+    #[test]
+    fn missing_schedule_charges_the_base_fee() {
+        let pool = scheduled_pool(FeeScheduleMode::None, ScheduleUnit::Slot, 0);
+        assert_eq!(pool.scheduled_fee_bps(100, 0).unwrap(), 25);
+
+        let mut pool = scheduled_pool(FeeScheduleMode::Linear, ScheduleUnit::Slot, 150);
+        pool.fee_schedule.period_frequency = 0;
+        assert_eq!(pool.scheduled_fee_bps(100, 0).unwrap(), 25);
+    }
+
+    #[test]
+    fn swap_exact_out_rounds_input_up() {
+        let price = get_price_from_id(1, 100).unwrap();
+        let mut bin = quote_bin(1_000_000);
+        let step = bin.swap_exact_out(100, price, 30, 0, true).unwrap();
+        assert_eq!(step.amount_out, 100);
+
+        // Paying that much in again buys at least as much
+        let mut bin = quote_bin(1_000_000);
+        let again = bin.swap_exact_in(step.amount_in, price, 30, 0, true).unwrap();
+        assert!(again.amount_out >= 100);
+    }
+
+    #[test]
+    fn swap_exact_out_is_capped_by_bin() {
+        let mut bin = quote_bin(500);
+        let step = bin.swap_exact_out(1_000, ONE, 0, 0, true).unwrap();
+        assert_eq!(step.amount_out, 500);
+        assert_eq!(step.amount_in, 500);
+        assert!(bin.is_empty(true));
+    }
+
+    #[test]
+    fn exact_out_swap_spans_bins() {
+        let mut pool = test_pool(0);
+        let mut bins = bin_arrays(&[0, -1]);
+        seed_quote(&mut bins, &mut pool, &[0, -1, -2], 1_000);
+
+        let result = pool.swap(&mut bins, SwapAmount::ExactOut(2_500), 30, 0, true, None, 0).unwrap();
+        assert_eq!(result.amount_out, 2_500);
+        assert_eq!(pool.active_bin_id, -2);
+        assert_eq!(bins.bin(-2).unwrap().amount_quote, 500);
+
+        // Inputs are rounded up bin by bin, so the same input buys it back
+        let mut again = test_pool(0);
+        let mut again_bins = bin_arrays(&[0, -1]);
+        seed_quote(&mut again_bins, &mut again, &[0, -1, -2], 1_000);
+        let exact_in = again
+            .swap(&mut again_bins, SwapAmount::ExactIn(result.amount_in), 30, 0, true, None, 0)
+            .unwrap();
+        assert!(exact_in.amount_out >= 2_500);
+    }
+
+    #[test]
+    fn exact_out_swap_fills_what_the_bins_hold() {
+        let mut pool = test_pool(0);
+        let mut bins = bin_arrays(&[0]);
+        seed_quote(&mut bins, &mut pool, &[0], 1_000);
+
+        let result = pool.swap(&mut bins, SwapAmount::ExactOut(5_000), 0, 0, true, None, 0).unwrap();
+        assert_eq!(result.amount_out, 1_000);
+        assert_eq!(result.amount_in, 1_000);
+    }
+
+    #[test]
+    fn limit_bin_stops_a_swap_partway() {
+        let mut pool = test_pool(0);
+        let mut bins = bin_arrays(&[0, -1]);
+        seed_quote(&mut bins, &mut pool, &[0, -1, -2, -3], 1_000);
+
+        let result = pool.swap(&mut bins, SwapAmount::ExactIn(1_000_000), 0, 0, true, Some(-1), 0).unwrap();
+        assert_eq!(result.amount_out, 2_000);
+        assert!(result.amount_in < 1_000_000);
+        assert_eq!(pool.active_bin_id, -1);
+        assert_eq!(bins.bin(-2).unwrap().amount_quote, 1_000);
+    }
+
+    #[test]
+    fn limit_bin_on_the_wrong_side_is_rejected() {
+        let mut pool = test_pool(0);
+        let mut bins = bin_arrays(&[0]);
+        assert!(pool.swap(&mut bins, SwapAmount::ExactIn(100), 0, 0, true, Some(1), 0).is_err());
+        assert!(pool.swap(&mut bins, SwapAmount::ExactIn(100), 0, 0, false, Some(-1), 0).is_err());
+    }
+
+    fn oracle(length: usize) -> Oracle {
+        Oracle {
+            pool: Pubkey::default(),
+            idx: 0,
+            observations: vec![Observation::default(); length],
+            bump: 0,
+        }
+    }
+
+    #[test]
+    fn oracle_accumulates_and_rolls_over() {
+        let mut oracle = oracle(3);
+        oracle.update(1_000, 5).unwrap();
+        oracle.update(1_060, 5).unwrap();
+        assert_eq!(oracle.idx, 0);
+        assert_eq!(oracle.observations[0].cumulative_active_bin_id, 300);
+
+        // A sample older than its lifetime starts the next slot
+        oracle.update(1_120, 5).unwrap();
+        assert_eq!(oracle.idx, 1);
+        assert_eq!(oracle.observations[1].cumulative_active_bin_id, 600);
+        assert_eq!(oracle.observations[1].created_at, 1_120);
+    }
+
+    #[test]
+    fn twap_averages_over_the_window() {
+        let mut oracle = oracle(3);
+        oracle.update(1_000, 5).unwrap();
+        oracle.update(1_060, 5).unwrap();
+        oracle.update(1_120, 5).unwrap();
+
+        // Bin 10 has been active since the last update
+        assert_eq!(oracle.twap(1_180, 10, 60).unwrap(), (10, 60));
+        // Interpolated between the two samples
+        assert_eq!(oracle.twap(1_180, 10, 90).unwrap(), (8, 90));
+        // Capped at the history there is
+        assert_eq!(oracle.twap(1_180, 10, 1_000).unwrap(), (7, 120));
+    }
+
+    #[test]
+    fn twap_needs_history() {
+        let oracle = oracle(3);
+        assert!(oracle.twap(1_000, 0, 60).is_err());
+
+        let mut oracle = oracle;
+        oracle.update(1_000, 0).unwrap();
+        assert!(oracle.twap(1_000, 0, 0).is_err());
+        assert!(oracle.twap(1_000, 0, 60).is_err());
+    }
+
+    #[test]
+    fn grow_keeps_the_ring_in_order() {
+        let mut oracle = oracle(2);
+        oracle.update(1_000, 1).unwrap();
+        oracle.update(1_120, 1).unwrap();
+        oracle.update(1_240, 1).unwrap();
+        assert_eq!(oracle.idx, 0);
+
+        oracle.grow(2);
+        assert_eq!(oracle.observations.len(), 4);
+        assert!(oracle.observations[1].is_empty() && oracle.observations[2].is_empty());
+        assert_eq!(oracle.observations[3].created_at, 1_120);
+
+        oracle.update(1_360, 1).unwrap();
+        assert_eq!(oracle.idx, 1);
+        assert_eq!(oracle.twap(1_360, 1, 10_000).unwrap(), (1, 240));
+    }
+
+    #[test]
+    fn swap_without_shares_sends_lp_fee_to_protocol() {
+        let mut bin = Bin::default();
+        bin.bid.place(1_000).unwrap();
+        let step = bin.swap_exact_in(1_000, ONE, 100, 2000, true).unwrap();
+
+        assert_eq!(step.fee, 10);
+        assert_eq!(step.protocol_fee, 2);
+        assert_eq!(step.unowned_lp_fee, 8);
+        assert_eq!(bin.fee_growth_base_x64, 0);
+    }
+
+    #[test]
+    fn swap_fills_orders_before_liquidity() {
+        let mut bin = quote_bin(500);
+        bin.bid.place(500).unwrap();
+        let step = bin.swap_exact_out(1_000, ONE, 0, 0, true).unwrap();
+
+        assert_eq!(step.order_out, 500);
+        assert_eq!(step.order_in, 500);
+        assert_eq!(bin.bid.amount, 0);
+        assert_eq!(bin.bid.filled, 500);
+        assert_eq!(bin.bid.factor_x64, 0);
+        assert_eq!(bin.amount_base, 500);
+        assert_eq!(bin.amount_quote, 0);
+    }
+
+    fn order(side: &OrderSide, amount: u64, is_ask: bool) -> LimitOrder {
+        LimitOrder {
+            owner: Pubkey::default(),
+            pool: Pubkey::default(),
+            bin_id: 0,
+            is_ask,
+            amount,
+            generation: side.generation,
+            factor_x64: side.factor_x64,
+            converted_claimed: 0,
+        }
+    }
+
+    #[test]
+    fn order_fills_are_shared_pro_rata() {
+        let mut side = OrderSide::default();
+        side.place(600).unwrap();
+        let mut first = order(&side, 600, true);
+        side.place(400).unwrap();
+        let mut second = order(&side, 400, true);
+
+        side.fill(500, 250).unwrap();
+        assert_eq!(side.amount, 500);
+        assert_eq!(side.filled, 250);
+        assert_eq!(first.remaining(&side).unwrap(), 300);
+        assert_eq!(second.remaining(&side).unwrap(), 200);
+
+        // Asks sold base at half a quote each
+        let price = ONE / 2;
+        assert_eq!(first.take_converted(&mut side, price).unwrap(), 150);
+        assert_eq!(second.take_converted(&mut side, price).unwrap(), 100);
+        assert_eq!(first.take_converted(&mut side, price).unwrap(), 0);
+        assert_eq!(side.filled, 0);
+    }
+
+    #[test]
+    fn order_claims_never_exceed_filled() {
+        let mut side = OrderSide::default();
+        side.place(1_000).unwrap();
+        let mut first = order(&side, 1_000, false);
+
+        // Rounding in the fill leaves less in the bucket than the price implies
+        side.fill(400, 399).unwrap();
+        assert_eq!(first.take_converted(&mut side, ONE).unwrap(), 399);
+        assert_eq!(side.filled, 0);
+    }
+
+    #[test]
+    fn fully_filled_side_starts_a_new_generation() {
+        let mut side = OrderSide::default();
+        side.place(100).unwrap();
+        let first = order(&side, 100, true);
+
+        side.fill(100, 50).unwrap();
+        assert_eq!(first.remaining(&side).unwrap(), 0);
+
+        side.place(70).unwrap();
+        let second = order(&side, 70, true);
+        assert_eq!(side.generation, first.generation + 1);
+        assert_eq!(first.remaining(&side).unwrap(), 0);
+        assert_eq!(second.remaining(&side).unwrap(), 70);
+    }
+
+    #[test]
+    fn swap_moves_filled_orders_between_order_balances() {
+        let mut pool = test_pool(0);
+        let mut bins = bin_arrays(&[0]);
+        bins.bin(0).unwrap().bid.place(500).unwrap();
+        pool.order_quote = 500;
+
+        let result = pool.swap(&mut bins, SwapAmount::ExactIn(500), 0, 0, true, None, 0).unwrap();
+        assert_eq!(result.amount_out, 500);
+        assert_eq!(pool.order_quote, 0);
+        assert_eq!(pool.order_base, 500);
+        assert_eq!(bins.bin(0).unwrap().bid.filled, 500);
+    }
 
-fn calculate_swap_output(amount_in: u64) -> u64 {
-    // Simplified constant product - real DLMM uses bin math
-    (amount_in as u128 * 99 / 100) as u64
+    #[test]
+    fn flash_loans_pay_the_scheduled_fee() {
+        let mut pool = scheduled_pool(FeeScheduleMode::Linear, ScheduleUnit::Slot, 150);
+        assert_eq!(pool.flash_loan_fee(10_000, 100, 0).unwrap(), 1_000);
+        assert_eq!(pool.flash_loan_fee(10_000, 150, 0).unwrap(), 25);
+        assert_eq!(pool.flash_loan_fee(1, 150, 0).unwrap(), 1);
+
+        pool.dynamic_fee_enabled = true;
+        pool.fee_params = dynamic_pool(0).fee_params;
+        pool.volatility.volatility_accumulator = 30_000;
+        assert_eq!(pool.flash_loan_fee(10_000, 150, 0).unwrap(), 61);
+    }
+
+    #[test]
+    fn emergency_payouts_are_pro_rata_to_shares() {
+        // The bins claim far more than the vault holds
+        let mut bins = bin_arrays(&[0]);
+        bins.bin(0).unwrap().deposit(0, 1_000_000, ONE).unwrap();
+        let mut pool = Pool {
+            status: PoolStatus::Emergency,
+            total_liquidity_shares: 400,
+            reserve_quote: 10_000,
+            liquidity_quote: 9_000,
+            protocol_fee_quote: 500,
+            creator_fee_quote: 100,
+            order_quote: 400,
+            ..test_pool(0)
+        };
+
+        // Only 6_000 is left in the vault, 5_000 of it for LPs
+        let first = pool.emergency_withdraw_shares(300, 0, 6_000).unwrap();
+        assert_eq!(first, (0, 3_750));
+        let second = pool.emergency_withdraw_shares(100, 0, 6_000 - 3_750).unwrap();
+        assert_eq!(second, (0, 1_250));
+
+        assert_eq!(pool.total_liquidity_shares, 0);
+        assert_eq!(pool.reserve_quote, 5_000);
+        // Fees and order funds are untouched
+        assert_eq!(pool.reserve_quote - pool.liquidity_quote, 1_000);
+    }
+
+    #[test]
+    fn emergency_payout_needs_shares() {
+        let mut pool = test_pool(0);
+        assert!(pool.emergency_withdraw_shares(0, 100, 100).is_err());
+    }
+
+    // One stream paying 10 tokens a second for 100 seconds from t = 0
+    fn rewarded_pool() -> Pool {
+        let mut pool = test_pool(0);
+        pool.rewards[0] = RewardInfo {
+            mint: Pubkey::new_unique(),
+            reward_duration: 100,
+            ..Default::default()
+        };
+        pool.rewards[0].restart(0, 1_000).unwrap();
+        pool
+    }
+
+    #[test]
+    fn rewards_accrue_to_the_active_bin() {
+        let mut pool = rewarded_pool();
+        let mut bins = bin_arrays(&[0]);
+        bins.bin(0).unwrap().liquidity_supply = 10;
+        bins.bin(1).unwrap().liquidity_supply = 10;
+
+        pool.update_rewards(&mut bins, 10).unwrap();
+        assert_eq!(bins.bin(0).unwrap().reward_growth_x64[0], 10 << SCALE_OFFSET);
+        assert_eq!(bins.bin(1).unwrap().reward_growth_x64[0], 0);
+        assert_eq!(pool.rewards[0].last_update_time, 10);
+
+        // Uninitialized streams are left alone
+        assert_eq!(bins.bin(0).unwrap().reward_growth_x64[1], 0);
+        assert_eq!(pool.rewards[1].last_update_time, 0);
+    }
+
+    #[test]
+    fn empty_active_bin_sets_rewards_aside() {
+        let mut pool = rewarded_pool();
+        let mut bins = bin_arrays(&[0]);
+
+        pool.update_rewards(&mut bins, 10).unwrap();
+        assert_eq!(bins.bin(0).unwrap().reward_growth_x64[0], 0);
+        assert_eq!(pool.rewards[0].undistributed_x64, 100 << SCALE_OFFSET);
+    }
+
+    #[test]
+    fn rewards_stop_at_the_end_of_the_duration() {
+        let mut pool = rewarded_pool();
+        let mut bins = bin_arrays(&[0]);
+        bins.bin(0).unwrap().liquidity_supply = 10;
+
+        pool.update_rewards(&mut bins, 150).unwrap();
+        assert_eq!(bins.bin(0).unwrap().reward_growth_x64[0], 100 << SCALE_OFFSET);
+        pool.update_rewards(&mut bins, 200).unwrap();
+        assert_eq!(bins.bin(0).unwrap().reward_growth_x64[0], 100 << SCALE_OFFSET);
+        assert_eq!(pool.rewards[0].last_update_time, 200);
+    }
+
+    #[test]
+    fn rewards_skip_an_unloaded_active_bin() {
+        let mut pool = rewarded_pool();
+        pool.active_bin_id = MAX_BIN_PER_ARRAY as i32;
+        let mut bins = bin_arrays(&[0]);
+
+        pool.update_rewards(&mut bins, 10).unwrap();
+        assert_eq!(pool.rewards[0].last_update_time, 0);
+        assert_eq!(pool.rewards[0].undistributed_x64, 0);
+    }
+
+    #[test]
+    fn restart_carries_over_what_was_not_emitted() {
+        let mut pool = rewarded_pool();
+        let reward = &mut pool.rewards[0];
+
+        // Half of the first 1_000 is still to come
+        reward.restart(50, 500).unwrap();
+        assert_eq!(reward.reward_rate_x64, 10 << SCALE_OFFSET);
+        assert_eq!(reward.reward_duration_end, 150);
+        assert_eq!(reward.last_update_time, 50);
+    }
+
+    fn leaf(owner: &Pubkey, max_cap: u64) -> [u8; 32] {
+        hashv(&[&[0], owner.as_ref(), &max_cap.to_le_bytes()]).to_bytes()
+    }
+
+    fn node(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+        let (a, b) = if a <= b { (a, b) } else { (b, a) };
+        hashv(&[&[1], &a, &b]).to_bytes()
+    }
+
+    #[test]
+    fn whitelist_proofs() {
+        let owners = [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+        let leaves = [leaf(&owners[0], 100), leaf(&owners[1], 200), leaf(&owners[2], 300)];
+        let left = node(leaves[0], leaves[1]);
+        let root = node(left, leaves[2]);
+
+        assert!(verify_whitelist(&root, &owners[0], 100, &[leaves[1], leaves[2]]));
+        assert!(verify_whitelist(&root, &owners[1], 200, &[leaves[0], leaves[2]]));
+        assert!(verify_whitelist(&root, &owners[2], 300, &[left]));
+
+        assert!(!verify_whitelist(&root, &owners[0], 101, &[leaves[1], leaves[2]]));
+        assert!(!verify_whitelist(&root, &owners[2], 300, &[leaves[0]]));
+        assert!(!verify_whitelist(&root, &Pubkey::new_unique(), 100, &[leaves[1], leaves[2]]));
+        // An inner node is not a valid leaf
+        assert!(!verify_whitelist(&root, &owners[0], 100, &[]));
+    }
+
+    #[test]
+    fn single_wallet_whitelist() {
+        let owner = Pubkey::new_unique();
+        assert!(verify_whitelist(&leaf(&owner, 5), &owner, 5, &[]));
+    }
+
+    fn alpha_vault(total_deposited: u64, quote_spent: u64, base_bought: u64) -> AlphaVault {
+        AlphaVault {
+            pool: Pubkey::default(),
+            merkle_root: [0; 32],
+            quote_account: Pubkey::default(),
+            base_account: Pubkey::default(),
+            max_buying_cap: total_deposited,
+            max_price_bin_id: 0,
+            total_deposited,
+            quote_spent,
+            base_bought,
+            vesting_start: 100,
+            vesting_duration: 50,
+            filled: true,
+            bump: 0,
+        }
+    }
+
+    fn escrow(deposited: u64) -> AlphaEscrow {
+        AlphaEscrow {
+            alpha_vault: Pubkey::default(),
+            owner: Pubkey::default(),
+            max_cap: deposited,
+            deposited,
+            claimed_base: 0,
+            refunded: false,
+            bump: 0,
+        }
+    }
+
+    #[test]
+    fn alpha_vault_vesting() {
+        let vault = alpha_vault(1_000, 600, 3_000);
+        let escrow = escrow(250);
+
+        assert_eq!(vault.vested_base(&escrow, 0).unwrap(), 0);
+        assert_eq!(vault.vested_base(&escrow, 100).unwrap(), 0);
+        assert_eq!(vault.vested_base(&escrow, 101).unwrap(), 15);
+        assert_eq!(vault.vested_base(&escrow, 125).unwrap(), 375);
+        assert_eq!(vault.vested_base(&escrow, 149).unwrap(), 735);
+        assert_eq!(vault.vested_base(&escrow, 150).unwrap(), 750);
+        assert_eq!(vault.vested_base(&escrow, 1_000).unwrap(), 750);
+    }
+
+    #[test]
+    fn alpha_vault_without_vesting_unlocks_at_fill() {
+        let mut vault = alpha_vault(1_000, 1_000, 3_000);
+        vault.vesting_duration = 0;
+        assert_eq!(vault.vested_base(&escrow(1_000), 100).unwrap(), 3_000);
+    }
+
+    #[test]
+    fn alpha_vault_shares_round_down() {
+        let vault = alpha_vault(1_000, 999, 1_000);
+        let escrows = [escrow(333), escrow(333), escrow(334)];
+
+        let vested: u64 = escrows.iter().map(|e| vault.vested_base(e, 150).unwrap()).sum();
+        let refunded: u64 = escrows.iter().map(|e| vault.quote_refund(e).unwrap()).sum();
+        assert_eq!(vault.vested_base(&escrows[0], 150).unwrap(), 333);
+        assert!(vested <= vault.base_bought);
+        assert_eq!(vault.quote_refund(&escrows[0]).unwrap(), 0);
+        assert!(refunded <= vault.total_deposited - vault.quote_spent);
+    }
+
+    #[test]
+    fn alpha_vault_refund() {
+        let vault = alpha_vault(1_000, 600, 3_000);
+        assert_eq!(vault.quote_refund(&escrow(250)).unwrap(), 100);
+
+        // Nothing bought, everything comes back
+        let vault = alpha_vault(1_000, 0, 0);
+        assert_eq!(vault.quote_refund(&escrow(250)).unwrap(), 250);
+        assert_eq!(vault.vested_base(&escrow(250), 150).unwrap(), 0);
+    }
+
+    // Books a swap the way execute_swap does once the transfers are done
+    fn settle_swap(pool: &mut Pool, result: &SwapResult) {
+        pool.protocol_fee_base += result.protocol_fee;
+        pool.creator_fee_base += result.creator_fee;
+        pool.add_reserves(result.amount_in, 0).unwrap();
+        pool.sub_reserves(0, result.amount_out).unwrap();
+    }
+
+    #[test]
+    fn deposits_swaps_and_claims_keep_reserves_balanced() {
+        let mut pool = Pool {
+            protocol_fee_bps: 2_000,
+            creator_fee_bps: 1_000,
+            ..test_pool(0)
+        };
+        let mut bins = bin_arrays(&[0, -1]);
+        seed_quote(&mut bins, &mut pool, &[0, -1, -2], 100_000);
+        pool.add_reserves(0, 300_000).unwrap();
+        pool.check_balances().unwrap();
+
+        let result = pool.swap(&mut bins, SwapAmount::ExactIn(150_000), 30, 0, true, None, 0).unwrap();
+        assert!(result.protocol_fee > 0 && result.creator_fee > 0);
+        settle_swap(&mut pool, &result);
+        pool.check_balances().unwrap();
+
+        let result = pool.swap(&mut bins, SwapAmount::ExactOut(70_000), 30, 0, true, None, 0).unwrap();
+        settle_swap(&mut pool, &result);
+        pool.check_balances().unwrap();
+
+        // Protocol and creator fees claimed out of the vault
+        let (protocol_fee, creator_fee) = (pool.protocol_fee_base, pool.creator_fee_base);
+        pool.protocol_fee_base = 0;
+        pool.creator_fee_base = 0;
+        pool.sub_reserves(protocol_fee + creator_fee, 0).unwrap();
+        pool.check_balances().unwrap();
+
+        // A fee booked without its tokens breaks the balance
+        pool.lp_fee_base += 1;
+        assert!(pool.check_balances().is_err());
+    }
+
+    #[test]
+    fn skim_sweeps_only_the_surplus() {
+        let pool = Pool {
+            reserve_base: 1_000,
+            reserve_quote: 2_000,
+            liquidity_base: 900,
+            protocol_fee_base: 100,
+            liquidity_quote: 1_500,
+            order_quote: 500,
+            ..test_pool(0)
+        };
+        pool.check_balances().unwrap();
+
+        assert_eq!(pool.skimmable(1_007, 2_000), (7, 0));
+        // A vault short of its reserve has nothing to skim
+        assert_eq!(pool.skimmable(900, 2_050), (0, 50));
+    }
 }
@@ -161,6 +161,12 @@ pub struct Initialize<'info> {
     /// CHECK: PUDL mint
     pub pudl_mint: AccountInfo<'info>,
 
+    #[account(constraint = staking_vault.mint == pudl_mint.key())]
+    pub staking_vault: Account<'info, TokenAccount>,
+
+    #[account(constraint = rewards_vault.mint == pudl_mint.key())]
+    pub rewards_vault: Account<'info, TokenAccount>,
+
     #[account(mut)]
     pub authority: Signer<'info>,
 
@@ -1,5 +1,5 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, TokenAccount, Transfer, Mint};
+use anchor_spl::token_interface::{self, Mint, TokenAccount, TokenInterface, TransferChecked};
 
 declare_id!("Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnV");
 
@@ -29,16 +29,18 @@ pub mod pudl_treasury {
     ) -> Result<()> {
         let treasury = &mut ctx.accounts.treasury;
 
-        token::transfer(
+        token_interface::transfer_checked(
             CpiContext::new(
                 ctx.accounts.token_program.to_account_info(),
-                Transfer {
+                TransferChecked {
                     from: ctx.accounts.source.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
                     to: ctx.accounts.fee_vault.to_account_info(),
                     authority: ctx.accounts.pool_authority.to_account_info(),
                 },
             ),
             amount,
+            ctx.accounts.mint.decimals,
         )?;
 
         treasury.total_fees_collected += amount;
@@ -114,7 +116,7 @@ pub struct Initialize<'info> {
     )]
     pub treasury: Account<'info, Treasury>,
 
-    pub pudl_mint: Account<'info, Mint>,
+    pub pudl_mint: InterfaceAccount<'info, Mint>,
 
     #[account(mut)]
     pub authority: Signer<'info>,
@@ -131,16 +133,19 @@ pub struct RecordFee<'info> {
     )]
     pub treasury: Account<'info, Treasury>,
 
-    #[account(mut)]
-    pub source: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = mint)]
+    pub source: InterfaceAccount<'info, TokenAccount>,
 
-    #[account(mut)]
-    pub fee_vault: Account<'info, TokenAccount>,
+    #[account(mut, token::mint = mint)]
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mint::token_program = token_program)]
+    pub mint: InterfaceAccount<'info, Mint>,
 
     /// CHECK: Pool authority
     pub pool_authority: Signer<'info>,
 
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
@@ -153,10 +158,10 @@ pub struct HarvestAndConvert<'info> {
     pub treasury: Account<'info, Treasury>,
 
     #[account(mut)]
-    pub fee_vault: Account<'info, TokenAccount>,
+    pub fee_vault: InterfaceAccount<'info, TokenAccount>,
 
     #[account(mut)]
-    pub rewards_vault: Account<'info, TokenAccount>,
+    pub rewards_vault: InterfaceAccount<'info, TokenAccount>,
 
     /// CHECK: Burn address
     pub burn_address: AccountInfo<'info>,
@@ -164,7 +169,7 @@ pub struct HarvestAndConvert<'info> {
     /// CHECK: Ops wallet
     pub ops_wallet: AccountInfo<'info>,
 
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]